@@ -0,0 +1,36 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! These are resolved at link time like the APIs in `axvisor_api`, the hypervisor implements
+//! `VGicV3If` with `axvisor_api::api_impl`.
+
+use axaddrspace::{GuestPhysAddr, HostPhysAddr};
+use axvisor_api::vmm::{VCpuId, VMId};
+
+/// The API trait for the services the GICv3 emulation needs from the hypervisor.
+#[axvisor_api::api_def]
+pub trait VGicV3If {
+    /// Injects a virtual interrupt into a vCPU through its list registers.
+    ///
+    /// Unlike `axvisor_api::vmm::inject_interrupt`, `intid` covers the whole GICv3 INTID space.
+    /// If the vCPU is not running on the current physical CPU, the hypervisor is responsible for
+    /// queueing the interrupt until it is.
+    fn inject_virtual_interrupt(vm_id: VMId, vcpu_id: VCpuId, intid: u32);
+
+    /// Translates a guest physical address of a VM through its stage-2 page table.
+    ///
+    /// Returns `None` if `gpa` is not backed by the VM's memory, e.g. it is unmapped or belongs
+    /// to an emulated device.
+    fn translate_guest_phys(vm_id: VMId, gpa: GuestPhysAddr) -> Option<HostPhysAddr>;
+}
@@ -12,22 +12,36 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-//! WARNING: Identical mapping only!!
+//! Guest-provided addresses of ITS structures, i.e. the command queue, the tables of the root VM
+//! and the ITTs of trusted VMs, are translated through the VM's stage-2 page table. A command that
+//! cannot be translated stalls the virtual command queue, as reported by GITS_CREADR.Stalled.
+//!
+//! The ITTs of devices mapped by untrusted VMs are allocated by the hypervisor instead, so that
+//! the host ITS never writes its private ITT format to guest memory on their behalf.
 
+extern crate alloc;
+
+use alloc::{collections::BTreeMap, vec::Vec};
 use core::{cell::UnsafeCell, ptr};
 
-use axaddrspace::{GuestPhysAddr, GuestPhysAddrRange, HostPhysAddr};
+use axaddrspace::{device::AccessWidth, GuestPhysAddr, GuestPhysAddrRange, HostPhysAddr};
 use axdevice_base::BaseDeviceOps;
-use axvisor_api::memory::phys_to_virt;
-use log::{debug, trace};
+use axerrno::{ax_err, AxResult};
+use axvisor_api::{
+    memory::phys_to_virt,
+    vmm::{VCpuId, VMId},
+};
+use log::{debug, trace, warn};
 use memory_addr::PhysAddr;
-use spin::{Mutex, Once};
-
-use crate::v3::vgicr::get_lpt;
+use spin::Mutex;
 
 use super::{
+    host_gicr::{host_gicrs, host_rd_base_to_vcpu, rd_base_to_vcpu, vcpu_host_gicr, HostGicr},
+    lifecycle,
+    lpi::{claim_lpi, in_lpi_space, release_lpi},
     registers::*,
-    utils::{enable_one_lpi, perform_mmio_read, perform_mmio_write},
+    utils::{perform_mmio_read, perform_mmio_write, read_guest, translate_guest_range, width_mask},
+    vpe::{has_vpes, translate_cmd},
 };
 
 /// Virtual GITS registers.
@@ -60,6 +74,8 @@ pub struct Gits {
     pub host_gits_base: HostPhysAddr,
     /// Whether this is a root VM.
     pub is_root_vm: bool,
+    /// The VM this GITS belongs to.
+    pub vm_id: VMId,
 
     /// Virtual GITS registers.
     pub regs: UnsafeCell<VirtualGitsRegs>,
@@ -81,28 +97,64 @@ impl Gits {
         size: Option<usize>,
         host_gits_base: HostPhysAddr,
         is_root_vm: bool,
+        vm_id: VMId,
     ) -> Self {
         let size = size.unwrap_or(DEFAULT_GITS_SIZE); // 4K
         let regs = UnsafeCell::new(VirtualGitsRegs::default());
 
         // ensure cmdq and lpi prop table is initialized before VMs are up
-        let _ = get_cmdq(host_gits_base);
-        let _ = get_lpt(
-            crate::api_reexp::read_vgicd_typer(),
-            crate::api_reexp::get_host_gicr_base(),
-            None, // Use default size
-        );
+        lifecycle::create(host_gits_base);
+        if let Err(err) = lifecycle::attach_vm(vm_id) {
+            warn!("Failed to attach VM {vm_id} to the host ITS: {err:?}");
+        }
 
         Self {
             addr,
             size,
             host_gits_base,
             is_root_vm,
+            vm_id,
             regs,
         }
     }
 }
 
+impl Gits {
+    /// Reads a GITS_BASERn of the root VM from the host, showing the guest's table address
+    /// `shadow` instead of the translated one.
+    fn read_host_baser(&self, reg: usize, width: AccessWidth, shadow: usize) -> AxResult<usize> {
+        let host = perform_mmio_read(self.host_gits_base + reg, width)?;
+        Ok((host & !GITS_BASER_PA_MASK) | (shadow & GITS_BASER_PA_MASK & width_mask(width)))
+    }
+
+    /// Writes a GITS_BASERn of the root VM to the host, with its table translated to host
+    /// physical memory.
+    ///
+    /// Indirect tables are made flat, as their level-2 entries would hold guest addresses the ITS
+    /// cannot translate. The guest notices on read-back, as with an ITS without indirect tables.
+    fn write_host_baser(&self, reg: usize, width: AccessWidth, val: usize) -> AxResult {
+        let mut host_val = val & !GITS_BASER_INDIRECT;
+        if val & GITS_BASER_VALID != 0 {
+            let page_size = match (val >> GITS_BASER_PAGE_SIZE_SHIFT) & 0b11 {
+                0 => 0x1000,
+                1 => 0x4000,
+                _ => 0x10000,
+            };
+            let size = ((val & GITS_BASER_SIZE_MASK) + 1) * page_size;
+            let gpa = GuestPhysAddr::from_usize(val & GITS_BASER_PA_MASK);
+            let hpa = match translate_guest_range(self.vm_id, gpa, size) {
+                Ok(hpa) => hpa,
+                Err(err) => {
+                    warn!("VM {} ITS table at {gpa:?}: {err:?}", self.vm_id);
+                    return Err(err);
+                }
+            };
+            host_val = (host_val & !GITS_BASER_PA_MASK) | hpa.as_usize();
+        }
+        perform_mmio_write(self.host_gits_base + reg, width, host_val)
+    }
+}
+
 impl BaseDeviceOps<GuestPhysAddrRange> for Gits {
     fn emu_type(&self) -> axdevice_base::EmuDeviceType {
         // todo: determine the correct type
@@ -136,7 +188,7 @@ impl BaseDeviceOps<GuestPhysAddrRange> for Gits {
             GITS_CBASER => Ok(self.regs().cbaser),
             GITS_DT_BASER => {
                 if self.is_root_vm {
-                    perform_mmio_read(gits_base + reg, width)
+                    self.read_host_baser(reg, width, self.regs().dt_baser)
                 } else {
                     Ok(
                         (self.regs().dt_baser)
@@ -146,7 +198,7 @@ impl BaseDeviceOps<GuestPhysAddrRange> for Gits {
             }
             GITS_CT_BASER => {
                 if self.is_root_vm {
-                    perform_mmio_read(gits_base + reg, width)
+                    self.read_host_baser(reg, width, self.regs().ct_baser)
                 } else {
                     Ok(
                         (self.regs().ct_baser)
@@ -184,41 +236,54 @@ impl BaseDeviceOps<GuestPhysAddrRange> for Gits {
         match reg {
             GITS_CTRL => perform_mmio_write(gits_base + reg, width, val),
             GITS_CBASER => {
-                if self.is_root_vm {
-                    perform_mmio_write(gits_base + reg, width, val)?;
+                // The host command queue belongs to the hypervisor, the guest queue is only read
+                // by it, so it just has to be backed by VM memory.
+                if val & GITS_BASER_VALID != 0 {
+                    let size = ((val & GITS_CBASER_SIZE_MASK) + 1) * 0x1000;
+                    let gpa = GuestPhysAddr::from_usize(val & GITS_CBASER_PA_MASK);
+                    if let Err(err) = translate_guest_range(self.vm_id, gpa, size) {
+                        warn!("VM {} command queue at {gpa:?}: {err:?}", self.vm_id);
+                        return Err(err);
+                    }
                 }
 
+                // Writing GITS_CBASER resets GITS_CREADR to the start of the new queue.
                 self.regs_mut().cbaser = val;
+                self.regs_mut().creadr = 0;
                 Ok(())
             }
             GITS_DT_BASER => {
                 if self.is_root_vm {
-                    perform_mmio_write(gits_base + reg, width, val)
-                } else {
-                    self.regs_mut().dt_baser = val;
-                    Ok(())
+                    self.write_host_baser(reg, width, val)?;
                 }
+                self.regs_mut().dt_baser = val;
+                Ok(())
             }
             GITS_CT_BASER => {
                 if self.is_root_vm {
-                    perform_mmio_write(gits_base + reg, width, val)
-                } else {
-                    self.regs_mut().ct_baser = val;
-                    Ok(())
+                    self.write_host_baser(reg, width, val)?;
                 }
+                self.regs_mut().ct_baser = val;
+                Ok(())
             }
             GITS_CWRITER => {
                 self.regs_mut().cwriter = val;
 
-                if val != 0 {
-                    let regs = self.regs();
-                    let cbaser = regs.cbaser;
-                    let creadr = regs.creadr;
-
-                    let mut cmdq = get_cmdq(self.host_gits_base).lock();
-                    self.regs_mut().creadr = cmdq.insert_cmd(cbaser, creadr, val);
+                // CWRITER wraps to 0 at the end of the queue, so 0 may carry commands too.
+                let regs = self.regs();
+                let cbaser = regs.cbaser;
+                let creadr = regs.creadr;
+                if creadr & GITS_CREADR_STALLED != 0 && val & GITS_CWRITER_RETRY == 0 {
+                    return Ok(());
                 }
 
+                let mut cmdq = CMDQ.lock();
+                let Some(cmdq) = cmdq.as_mut() else {
+                    return ax_err!(BadState, "Host ITS is not initialized");
+                };
+                self.regs_mut().creadr =
+                    cmdq.insert_cmd(self.vm_id, self.is_root_vm, cbaser, creadr, val);
+
                 Ok(())
             }
             GITS_CREADR => {
@@ -233,6 +298,8 @@ impl BaseDeviceOps<GuestPhysAddrRange> for Gits {
 /// Command queue for GITS commands.
 pub struct Cmdq {
     phy_addr: PhysAddr,
+    /// Size of the host command queue in bytes.
+    size: usize,
     readr: usize,
     writer: usize,
 
@@ -240,31 +307,60 @@ pub struct Cmdq {
 
     dt_addr: HostPhysAddr, // device table addr
     ct_addr: HostPhysAddr, // command table addr
+
+    /// ITTs allocated by the hypervisor that the commands being forwarded unmap or replace,
+    /// freed once the host ITS has consumed them.
+    stale_itts: Vec<HostItt>,
 }
 
 impl Drop for Cmdq {
     fn drop(&mut self) {
-        trace!("Cmdq dealloc 16 frames: {:?}", self.phy_addr);
-        axvisor_api::memory::dealloc_contiguous_frames(self.phy_addr, 16)
+        // The ITS must be quiescent before its queue and tables go away.
+        let ctlr_addr = self.host_gits_base + GITS_CTRL;
+        let _ = perform_mmio_write(ctlr_addr, AccessWidth::Dword, 0);
+        while perform_mmio_read(ctlr_addr, AccessWidth::Dword)
+            .is_ok_and(|ctlr| ctlr & GITS_CTLR_QUIESCENT == 0)
+        {
+            core::hint::spin_loop();
+        }
+        for reg in [GITS_CBASER, GITS_DT_BASER, GITS_CT_BASER] {
+            let _ = perform_mmio_write(self.host_gits_base + reg, AccessWidth::Qword, 0);
+        }
+
+        trace!("Cmdq dealloc {HOST_CMDQ_PAGES} frames: {:?}", self.phy_addr);
+        axvisor_api::memory::dealloc_contiguous_frames(self.phy_addr, HOST_CMDQ_PAGES);
+        for table in [self.dt_addr, self.ct_addr] {
+            if table.as_usize() != 0 {
+                axvisor_api::memory::dealloc_contiguous_frames(table, 16);
+            }
+        }
     }
 }
 
 /// Bytes per GITS command.
 pub const BYTES_PER_CMD: usize = 0x20;
+/// RDbase field of MAPC, MOVALL and SYNC commands.
+const RD_BASE_MASK: u64 = 0x7_ffff_ffff << 16;
 /// Quadwords per GITS command.
 pub const QWORD_PER_CMD: usize = BYTES_PER_CMD >> 3; // 8 bytes per qword
+/// Number of 4 KiB pages of the host command queue.
+const HOST_CMDQ_PAGES: usize = 16;
+/// ITT Address field of MAPD commands.
+const ITT_ADDR_MASK: u64 = 0x000f_ffff_ffff_ff00;
 
 impl Cmdq {
     fn new(host_gits_base: HostPhysAddr) -> Self {
-        let phy_addr = axvisor_api::memory::alloc_contiguous_frames(16, 0).unwrap();
-        trace!("Cmdq alloc 16 frames: {phy_addr:?}");
+        let phy_addr = axvisor_api::memory::alloc_contiguous_frames(HOST_CMDQ_PAGES, 0).unwrap();
+        trace!("Cmdq alloc {HOST_CMDQ_PAGES} frames: {phy_addr:?}");
         let mut r = Self {
             phy_addr,
+            size: HOST_CMDQ_PAGES * 0x1000,
             readr: 0,
             writer: 0,
             host_gits_base,
             dt_addr: 0.into(),
             ct_addr: 0.into(),
+            stale_itts: Vec::new(),
         };
         r.init_real_cbaser();
         r
@@ -273,7 +369,7 @@ impl Cmdq {
     fn init_real_cbaser(&mut self) {
         let cbaser_addr = self.host_gits_base + GITS_CBASER;
         let cwriter_addr = self.host_gits_base + GITS_CWRITER;
-        let cbaser_val = 0xb80000000000040f | self.phy_addr.as_usize();
+        let cbaser_val = 0xb800000000000400 | (HOST_CMDQ_PAGES - 1) | self.phy_addr.as_usize();
         let ctlr_addr = self.host_gits_base + GITS_CTRL;
 
         let cbaser_ptr = phys_to_virt(cbaser_addr).as_mut_ptr_of::<u64>();
@@ -337,20 +433,38 @@ impl Cmdq {
     }
 
     // it's ok to add qemu-args: -trace gicv3_gits_cmd_*, remember to remain `enable one lpi`
-    fn analyze_cmd(&self, value: [u64; 4]) {
+    /// Returns the command to forward to the host ITS, if any, or an error if the command cannot
+    /// be handled and must stall the guest's command queue.
+    ///
+    /// Commands of untrusted VMs that fail [`Cmdq::sanitize_cmd`] are dropped and counted.
+    fn analyze_cmd(
+        &mut self,
+        vm_id: VMId,
+        trusted: bool,
+        mut value: [u64; 4],
+    ) -> AxResult<Option<[u64; 4]>> {
+        if !trusted {
+            if let Err(err) = self.sanitize_cmd(vm_id, &value) {
+                warn!("VM {vm_id} ITS command {value:#x?} rejected: {err:?}");
+                ITS_GRANTS.lock().entry(vm_id).or_default().rejected += 1;
+                return Ok(None);
+            }
+        }
+        value = remap_device(vm_id, value);
+
         let code = (value[0] & 0xff) as usize;
         match code {
             0x0b => {
                 let id = value[0] & 0xffffffff00000000;
                 let event = value[1] & 0xffffffff;
                 let icid = value[2] & 0xffff;
-                enable_one_lpi((event - 8192) as _);
                 debug!(
                     "MAPI cmd, for device {:#x}, event = intid = {:#x} -> icid {:#x}",
                     id >> 32,
                     event,
                     icid
                 );
+                return Ok(map_lpi(vm_id, value, event as u32));
             }
             0x08 => {
                 let id = value[0] & 0xffffffff00000000;
@@ -360,13 +474,39 @@ impl Cmdq {
                     itt_base,
                     id >> 32
                 );
+                let device_id = (id >> 32) as u32;
+                if value[2] & (1 << 63) != 0 {
+                    let itt = if trusted {
+                        value = self.translate_itt(vm_id, value)?;
+                        None
+                    } else {
+                        let (cmd, itt) = self.host_itt(value)?;
+                        value = cmd;
+                        Some(itt)
+                    };
+                    let mut mappings = HOST_ITS_MAPPINGS.lock();
+                    mappings.devices.insert(device_id, vm_id);
+                    let old = match itt {
+                        Some(itt) => mappings.itts.insert(device_id, itt),
+                        None => mappings.itts.remove(&device_id),
+                    };
+                    self.stale_itts.extend(old);
+                } else {
+                    let old = {
+                        let mut mappings = HOST_ITS_MAPPINGS.lock();
+                        mappings.devices.remove(&device_id);
+                        mappings.itts.remove(&device_id)
+                    };
+                    self.stale_itts.extend(old);
+                    // Unmapping a device drops its events too.
+                    unmap_events(device_id, None);
+                }
             }
             0x0a => {
                 let id = value[0] & 0xffffffff00000000;
                 let event = value[1] & 0xffffffff;
                 let intid = value[1] >> 32;
                 let icid = value[2] & 0xffff;
-                enable_one_lpi((intid - 8192) as _);
                 debug!(
                     "MAPTI cmd, for device {:#x}, event {:#x} -> icid {:#x} + intid {:#x}",
                     id >> 32,
@@ -374,20 +514,53 @@ impl Cmdq {
                     icid,
                     intid
                 );
+                return Ok(map_lpi(vm_id, value, intid as u32));
             }
             0x09 => {
                 let icid = value[2] & 0xffff;
                 let rd_base = (value[2] >> 16) & 0x7ffffffff;
                 debug!("MAPC cmd, icid {icid:#x} -> redist {rd_base:#x}");
+                if value[2] & (1 << 63) != 0 {
+                    match self.translate_mapc(vm_id, trusted, value) {
+                        Ok(cmd) => value = cmd,
+                        Err(err) => {
+                            warn!("VM {vm_id} MAPC RDbase {rd_base:#x} dropped: {err:?}");
+                            return Ok(None);
+                        }
+                    }
+                } else if !has_vpes(vm_id) {
+                    HOST_ITS_MAPPINGS.lock().collections.remove(&(icid as u16));
+                }
             }
             0x05 => {
-                debug!("SYNC cmd");
+                let rd_base = value[2] & RD_BASE_MASK;
+                debug!("SYNC cmd, redist {:#x}", rd_base >> 16);
+                match self.translate_rd_base(vm_id, trusted, rd_base) {
+                    Ok((_, target)) => value[2] = (value[2] & !RD_BASE_MASK) | target,
+                    Err(err) => {
+                        warn!("VM {vm_id} SYNC RDbase {rd_base:#x} dropped: {err:?}");
+                        return Ok(None);
+                    }
+                }
             }
             0x04 => {
                 debug!("CLEAR cmd");
             }
+            0x01 => {
+                let (device_id, event_id) = ((value[0] >> 32) as u32, value[1] as u32);
+                debug!("MOVI cmd, device {device_id:#x} event {event_id:#x}");
+                if let Some(event) = HOST_ITS_MAPPINGS
+                    .lock()
+                    .events
+                    .values_mut()
+                    .find(|&&mut (_, dev, event, _)| (dev, event) == (device_id, event_id))
+                {
+                    event.3 = value[2] as u16;
+                }
+            }
             0x0f => {
                 debug!("DISCARD cmd");
+                unmap_events((value[0] >> 32) as u32, Some(value[1] as u32));
             }
             0x03 => {
                 debug!("INT cmd");
@@ -402,16 +575,206 @@ impl Cmdq {
                 debug!("other cmd, code: 0x{code:x}");
             }
         }
+
+        // VMs with vPEs get their interrupts mapped as virtual LPIs.
+        if has_vpes(vm_id) {
+            return Ok(translate_cmd(vm_id, value));
+        }
+        Ok(Some(value))
     }
 
-    /// WARNING: this function supports only GPA-HPA identical mapping!
-    fn insert_cmd(&mut self, vm_cbaser: usize, vm_creadr: usize, vm_writer: usize) -> usize {
-        let vm_addr = vm_cbaser & 0xf_ffff_ffff_f000;
+    /// Checks that a command of a VM only touches the VM's own resources: DeviceIDs granted to it
+    /// with [`grant_its_device`] or [`map_its_device`], collections it has mapped itself (or, for
+    /// MAPC, that no other VM has mapped), redistributors of its own vCPUs and LPIs in its LPI
+    /// space.
+    ///
+    /// MOVALL, which moves the LPIs of every VM on a redistributor, and the GICv4 commands are
+    /// reserved to the hypervisor.
+    fn sanitize_cmd(&self, vm_id: VMId, cmd: &[u64; 4]) -> AxResult {
+        let code = cmd[0] & 0xff;
+        let device_id = (cmd[0] >> 32) as u32;
+        let icid = cmd[2] as u16;
+
+        let (uses_device, uses_icid) = match code {
+            ITS_CMD_MAPD | ITS_CMD_DISCARD | ITS_CMD_INT | ITS_CMD_CLEAR | ITS_CMD_INV => {
+                (true, false)
+            }
+            ITS_CMD_MAPTI | ITS_CMD_MAPI | ITS_CMD_MOVI => (true, true),
+            ITS_CMD_MAPC | ITS_CMD_INVALL => (false, true),
+            ITS_CMD_SYNC => (false, false),
+            ITS_CMD_MOVALL => return ax_err!(PermissionDenied, "MOVALL is reserved"),
+            _ => return ax_err!(Unsupported, "Unknown or reserved ITS command"),
+        };
+
+        if uses_device
+            && !ITS_GRANTS
+                .lock()
+                .get(&vm_id)
+                .is_some_and(|grants| grants.devices.contains_key(&device_id))
+        {
+            return ax_err!(PermissionDenied, "DeviceID is not granted to the VM");
+        }
 
-        let origin_vm_readr = vm_creadr;
+        // Collections of VMs with vPEs are virtual.
+        if uses_icid && !has_vpes(vm_id) {
+            let owner = HOST_ITS_MAPPINGS
+                .lock()
+                .collections
+                .get(&icid)
+                .map(|&(owner, _)| owner);
+            match owner {
+                Some(owner) if owner != vm_id => {
+                    return ax_err!(PermissionDenied, "Collection belongs to another VM");
+                }
+                // Only MAPC may name a collection the VM has not mapped yet.
+                None if code != ITS_CMD_MAPC => {
+                    return ax_err!(PermissionDenied, "Collection is not mapped by the VM");
+                }
+                _ => {}
+            }
+        }
 
-        // todo: handle wrap around
-        let cmd_size = vm_writer - origin_vm_readr;
+        if (code == ITS_CMD_SYNC || code == ITS_CMD_MAPC && cmd[2] & (1 << 63) != 0)
+            && rd_base_to_vcpu(vm_id, cmd[2] & RD_BASE_MASK, self.host_pta()?).is_none()
+        {
+            return ax_err!(
+                PermissionDenied,
+                "RDbase does not name a redistributor of the VM"
+            );
+        }
+
+        if matches!(code, ITS_CMD_MAPTI | ITS_CMD_MAPI) {
+            let intid = if code == ITS_CMD_MAPTI {
+                (cmd[1] >> 32) as u32
+            } else {
+                cmd[1] as u32
+            };
+            if !in_lpi_space(vm_id, intid) {
+                return ax_err!(InvalidInput, "INTID is not in the LPI space of the VM");
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads GITS_TYPER.PTA of the host ITS, which the guest sees too.
+    fn host_pta(&self) -> AxResult<bool> {
+        let typer = perform_mmio_read(self.host_gits_base + GITS_TYPER, AccessWidth::Qword)?;
+        Ok(typer & GITS_TYPER_PTA != 0)
+    }
+
+    /// Resolves the RDbase field `rd_base` (in place) of a VM's MAPC or SYNC command to the vCPU
+    /// it names, and the RDbase to use on the host: the host redistributor the vCPU is bound to,
+    /// or the vCPU ID for VMs with vPEs, as their collections are virtual.
+    ///
+    /// The guest names redistributors in the encoding of GITS_TYPER.PTA, by the guest physical
+    /// address of its VGicR or by its processor number. Trusted VMs may also name the physical
+    /// redistributor one of their vCPUs is bound to.
+    fn translate_rd_base(
+        &self,
+        vm_id: VMId,
+        trusted: bool,
+        rd_base: u64,
+    ) -> AxResult<(VCpuId, u64)> {
+        let pta = self.host_pta()?;
+        let vcpu_id = rd_base_to_vcpu(vm_id, rd_base, pta)
+            .or_else(|| trusted.then(|| host_rd_base_to_vcpu(vm_id, rd_base, pta))?);
+        let Some(vcpu_id) = vcpu_id else {
+            return ax_err!(NotFound, "RDbase does not name a redistributor of the VM");
+        };
+
+        if has_vpes(vm_id) {
+            return Ok((vcpu_id, (vcpu_id as u64) << 16));
+        }
+        match vcpu_host_gicr(vm_id, vcpu_id) {
+            Some(gicr) => Ok((vcpu_id, gicr.rd_target(pta))),
+            None => ax_err!(NotFound, "vCPU is not bound to a host redistributor"),
+        }
+    }
+
+    /// Translates the RDbase of a MAPC command with V == 1 to the host, and records the
+    /// collection as the VM's when it is mapped on the host ITS.
+    fn translate_mapc(&self, vm_id: VMId, trusted: bool, mut cmd: [u64; 4]) -> AxResult<[u64; 4]> {
+        let (vcpu_id, target) = self.translate_rd_base(vm_id, trusted, cmd[2] & RD_BASE_MASK)?;
+        if !has_vpes(vm_id) {
+            HOST_ITS_MAPPINGS
+                .lock()
+                .collections
+                .insert(cmd[2] as u16, (vm_id, vcpu_id));
+        }
+        debug!("VM {vm_id} MAPC vCPU {vcpu_id} -> RDbase {target:#x}");
+        cmd[2] = (cmd[2] & !RD_BASE_MASK) | target;
+        Ok(cmd)
+    }
+
+    /// Translates the ITT address of a MAPD command of a trusted VM from guest to host physical
+    /// memory.
+    fn translate_itt(&self, vm_id: VMId, mut cmd: [u64; 4]) -> AxResult<[u64; 4]> {
+        let typer = perform_mmio_read(self.host_gits_base + GITS_TYPER, AccessWidth::Qword)?;
+        let entry_size = ((typer >> GITS_TYPER_ITT_ENTRY_SIZE_SHIFT) & 0xf) + 1;
+        let events = 1usize << ((cmd[1] & 0x1f) + 1);
+        let gpa = GuestPhysAddr::from_usize((cmd[2] & ITT_ADDR_MASK) as usize);
+        let hpa = translate_guest_range(vm_id, gpa, events * entry_size)?;
+
+        cmd[2] = (cmd[2] & !ITT_ADDR_MASK) | hpa.as_usize() as u64;
+        Ok(cmd)
+    }
+
+    /// Allocates a zeroed ITT in hypervisor memory for a MAPD command of an untrusted VM, sized
+    /// from MAPD.Size and the host ITT entry size, and puts its address in the command instead
+    /// of the guest's.
+    fn host_itt(&self, mut cmd: [u64; 4]) -> AxResult<([u64; 4], HostItt)> {
+        let typer = perform_mmio_read(self.host_gits_base + GITS_TYPER, AccessWidth::Qword)?;
+        let entry_size = ((typer >> GITS_TYPER_ITT_ENTRY_SIZE_SHIFT) & 0xf) + 1;
+        let id_bits = ((typer >> GITS_TYPER_IDBITS_SHIFT) & 0x1f) + 1;
+        let event_bits = (cmd[1] & 0x1f) as usize + 1;
+        if event_bits > id_bits {
+            return ax_err!(InvalidInput, "ITT size exceeds the host EventID bits");
+        }
+
+        let pages = ((1usize << event_bits) * entry_size).div_ceil(memory_addr::PAGE_SIZE_4K);
+        let Some(frame) = axvisor_api::memory::alloc_contiguous_frames(pages, 0) else {
+            return ax_err!(NoMemory, "Failed to allocate an ITT");
+        };
+        unsafe {
+            ptr::write_bytes(
+                phys_to_virt(frame).as_mut_ptr(),
+                0,
+                pages * memory_addr::PAGE_SIZE_4K,
+            );
+        }
+        trace!("ITT alloc {pages} frames: {frame:?}");
+
+        cmd[2] = (cmd[2] & !ITT_ADDR_MASK) | frame.as_usize() as u64;
+        Ok((cmd, HostItt { frame, pages }))
+    }
+
+    /// Forwards the commands the guest queued between `vm_creadr` and `vm_writer` to the host,
+    /// returning the new guest CREADR.
+    ///
+    /// Both offsets wrap around the guest queue, whose size is given by GITS_CBASER.Size. A
+    /// command that cannot be read or handled stalls the queue: the returned CREADR points at it
+    /// with Stalled set, until the guest retries with GITS_CWRITER.Retry.
+    fn insert_cmd(
+        &mut self,
+        vm_id: VMId,
+        trusted: bool,
+        vm_cbaser: usize,
+        vm_creadr: usize,
+        vm_writer: usize,
+    ) -> usize {
+        let vm_addr = vm_cbaser & GITS_CBASER_PA_MASK;
+        let vm_size = ((vm_cbaser & GITS_CBASER_SIZE_MASK) + 1) * 0x1000;
+
+        let origin_vm_readr = vm_creadr & GITS_CQ_OFFSET_MASK;
+        let vm_writer = vm_writer & GITS_CQ_OFFSET_MASK;
+        if vm_writer >= vm_size || origin_vm_readr >= vm_size {
+            warn!(
+                "VM {vm_id} CWRITER {vm_writer:#x} is beyond its {vm_size:#x} bytes command queue"
+            );
+            return vm_creadr;
+        }
+
+        let cmd_size = ring_distance(origin_vm_readr, vm_writer, vm_size);
         let cmd_num = cmd_size / BYTES_PER_CMD;
 
         trace!(
@@ -419,41 +782,82 @@ impl Cmdq {
         );
         debug!("cmd size: {cmd_size:#x}, cmd num: {cmd_num:#x}");
 
-        let mut vm_cmdq_addr = PhysAddr::from_usize(vm_addr + origin_vm_readr);
-        let mut real_cmdq_addr = self.phy_addr + self.readr;
+        let mut vm_readr = origin_vm_readr;
 
         for _cmd_id in 0..cmd_num {
-            let vm_cmdq_ptr = phys_to_virt(vm_cmdq_addr).as_mut_ptr_of::<[u64; QWORD_PER_CMD]>();
-            let mut real_cmdq_ptr = phys_to_virt(real_cmdq_addr).as_mut_ptr_of::<u64>();
+            let vm_cmdq_addr = GuestPhysAddr::from_usize(vm_addr + vm_readr);
+            let cmd = read_guest::<[u64; QWORD_PER_CMD]>(vm_id, vm_cmdq_addr)
+                .and_then(|v| self.analyze_cmd(vm_id, trusted, v));
+            match cmd {
+                Ok(Some(cmd)) => self.push_cmd(cmd),
+                Ok(None) => {}
+                Err(err) => {
+                    warn!("VM {vm_id} ITS command at {vm_cmdq_addr:?} stalls the queue: {err:?}");
+                    self.flush();
+                    self.stale_itts.clear();
+                    return vm_readr | GITS_CREADR_STALLED;
+                }
+            }
+
+            vm_readr = ring_ptr_update(vm_readr + BYTES_PER_CMD, vm_size);
+        }
 
-            unsafe {
-                let v = ptr::read_volatile(vm_cmdq_ptr);
-                self.analyze_cmd(v);
+        self.flush();
+        // The host ITS is done with the ITTs of the devices it unmapped or remapped.
+        self.stale_itts.clear();
 
-                for &one in v.iter().take(QWORD_PER_CMD) {
-                    ptr::write_volatile(real_cmdq_ptr, one);
-                    real_cmdq_addr += 8;
-                    real_cmdq_ptr = real_cmdq_ptr.add(1);
-                }
+        vm_writer
+    }
+
+    /// Copies a command into the host command queue, without notifying the ITS.
+    ///
+    /// If the queue is full, the queued commands are handed to the ITS first and this waits until
+    /// it has consumed enough of them.
+    fn push_cmd(&mut self, cmd: [u64; QWORD_PER_CMD]) {
+        let next = ring_ptr_update(self.writer + BYTES_PER_CMD, self.size);
+        if next == self.readr {
+            self.wait_for_space(next);
+        }
+
+        let mut real_cmdq_ptr = phys_to_virt(self.phy_addr + self.writer).as_mut_ptr_of::<u64>();
+        unsafe {
+            for &one in cmd.iter() {
+                ptr::write_volatile(real_cmdq_ptr, one);
+                real_cmdq_ptr = real_cmdq_ptr.add(1);
+            }
+        }
+        self.writer = next; // ring buffer ptr
+    }
+
+    /// Publishes the queued commands and waits until the ITS has read past `next`, the slot after
+    /// the next command, so that the queue is not full anymore.
+    fn wait_for_space(&mut self, next: usize) {
+        debug!(
+            "host cmdq full, readr={:#x}, writer={:#x}",
+            self.readr, self.writer
+        );
+        let cwriter_ptr = phys_to_virt(self.host_gits_base + GITS_CWRITER).as_mut_ptr_of::<u64>();
+        let creadr_ptr = phys_to_virt(self.host_gits_base + GITS_CREADR).as_mut_ptr_of::<u64>();
+        unsafe {
+            ptr::write_volatile(cwriter_ptr, self.writer as _);
+            while self.readr == next {
+                core::hint::spin_loop();
+                self.readr = ptr::read_volatile(creadr_ptr) as usize & GITS_CQ_OFFSET_MASK;
             }
-            vm_cmdq_addr += BYTES_PER_CMD;
-            vm_cmdq_addr = (ring_ptr_update(vm_cmdq_addr.as_usize() - vm_addr) + vm_addr).into();
-            real_cmdq_addr =
-                (ring_ptr_update(real_cmdq_addr - self.phy_addr) + self.phy_addr.as_usize()).into();
         }
+    }
 
-        self.writer += cmd_size;
-        self.writer = ring_ptr_update(self.writer); // ring buffer ptr
+    /// Hands the pushed commands to the ITS and waits for it to consume them.
+    fn flush(&mut self) {
         let cwriter_addr = self.host_gits_base + GITS_CWRITER;
         let creadr_addr = self.host_gits_base + GITS_CREADR;
 
         let cwriter_ptr = phys_to_virt(cwriter_addr).as_mut_ptr_of::<u64>();
         let creadr_ptr = phys_to_virt(creadr_addr).as_mut_ptr_of::<u64>();
-        // let ctlr_ptr = phys_to_virt(self.host_gits_base + GITS_CTRL).as_mut_ptr_of::<u64>();
         unsafe {
             ptr::write_volatile(cwriter_ptr, self.writer as _);
             loop {
-                self.readr = (ptr::read_volatile(creadr_ptr)) as usize; // hw readr
+                self.readr = (ptr::read_volatile(creadr_ptr)) as usize & GITS_CQ_OFFSET_MASK; // hw readr
                 if self.readr == self.writer {
                     debug!(
                         "readr={:#x}, writer={:#x}, its cmd end",
@@ -463,25 +867,461 @@ impl Cmdq {
                 }
             }
         }
+    }
+}
 
-        vm_writer
+/// Sends commands built by the hypervisor to the host ITS and waits for their completion.
+pub(crate) fn send_host_cmds(cmds: &[[u64; QWORD_PER_CMD]]) -> AxResult {
+    let mut cmdq = CMDQ.lock();
+    let Some(cmdq) = cmdq.as_mut() else {
+        return ax_err!(BadState, "Host ITS is not initialized");
+    };
+
+    for &cmd in cmds {
+        cmdq.push_cmd(cmd);
     }
+    cmdq.flush();
+    Ok(())
 }
 
-static CMDQ: Once<Mutex<Cmdq>> = Once::new();
+/// Reads a register of the host ITS.
+pub(crate) fn read_host_gits(reg: usize) -> AxResult<usize> {
+    let Some(host_gits_base) = CMDQ.lock().as_ref().map(|cmdq| cmdq.host_gits_base) else {
+        return ax_err!(BadState, "Host ITS is not initialized");
+    };
+
+    perform_mmio_read(host_gits_base + reg, AccessWidth::Qword)
+}
 
-fn get_cmdq(host_gits_base: HostPhysAddr) -> &'static Mutex<Cmdq> {
-    if !CMDQ.is_completed() {
-        CMDQ.call_once(|| Mutex::new(Cmdq::new(host_gits_base)));
+/// Maps the LPI `intid` of a VM's LPI space for a MAPI or MAPTI command.
+///
+/// Returns the command to forward to the host ITS, mapping the physical LPI backing `intid`.
+/// Commands of VMs with vPEs become VMAPTI.
+fn map_lpi(vm_id: VMId, cmd: [u64; 4], intid: u32) -> Option<[u64; 4]> {
+    let physical = match claim_lpi(vm_id, intid) {
+        Ok(physical) => physical,
+        Err(err) => {
+            warn!("VM {vm_id} failed to map LPI {intid}: {err:?}");
+            return None;
+        }
+    };
+    if has_vpes(vm_id) {
+        return translate_cmd(vm_id, cmd);
     }
 
-    CMDQ.get().unwrap()
+    HOST_ITS_MAPPINGS.lock().events.insert(
+        physical,
+        (vm_id, (cmd[0] >> 32) as u32, cmd[1] as u32, cmd[2] as u16),
+    );
+
+    // MAPI becomes MAPTI, the physical LPI is not the EventID.
+    Some([
+        (cmd[0] & !0xff) | 0x0a,
+        (cmd[1] & 0xffff_ffff) | (physical as u64) << 32,
+        cmd[2],
+        cmd[3],
+    ])
+}
+
+/// Forgets the events of the physical device `device_id`, or only its event `event_id`, and
+/// releases the physical LPIs they were mapped to.
+fn unmap_events(device_id: u32, event_id: Option<u32>) {
+    let mut unmapped = Vec::new();
+    HOST_ITS_MAPPINGS
+        .lock()
+        .events
+        .retain(|&physical, &mut (owner, dev, event, _)| {
+            let hit = dev == device_id && event_id.is_none_or(|id| id == event);
+            if hit {
+                unmapped.push((owner, physical));
+            }
+            !hit
+        });
+    for (owner, physical) in unmapped {
+        if let Err(err) = release_lpi(owner, physical) {
+            warn!("VM {owner} failed to release LPI {physical}: {err:?}");
+        }
+    }
+}
+
+/// An ITT the hypervisor allocated for a device mapped by an untrusted VM, freed when dropped.
+///
+/// It must only be dropped once the host ITS has processed the MAPD that unmaps or remaps the
+/// device.
+struct HostItt {
+    frame: HostPhysAddr,
+    pages: usize,
+}
+
+impl Drop for HostItt {
+    fn drop(&mut self) {
+        trace!("ITT dealloc {} frames: {:?}", self.pages, self.frame);
+        axvisor_api::memory::dealloc_contiguous_frames(self.frame, self.pages);
+    }
+}
+
+/// Events, devices and collections mapped on the host ITS on behalf of VMs.
+#[derive(Default)]
+struct HostItsMappings {
+    /// Physical LPI to the VM, DeviceID and EventID mapped to it, and the ICID of the
+    /// collection it is routed through.
+    events: BTreeMap<u32, (VMId, u32, u32, u16)>,
+    /// Devices mapped with MAPD, to their VM.
+    devices: BTreeMap<u32, VMId>,
+    /// Collections mapped with MAPC, to their VM and the vCPU they target.
+    collections: BTreeMap<u16, (VMId, VCpuId)>,
+    /// ITTs allocated by the hypervisor, by DeviceID of the device they belong to.
+    itts: BTreeMap<u32, HostItt>,
+}
+
+static HOST_ITS_MAPPINGS: Mutex<HostItsMappings> = Mutex::new(HostItsMappings {
+    events: BTreeMap::new(),
+    devices: BTreeMap::new(),
+    collections: BTreeMap::new(),
+    itts: BTreeMap::new(),
+});
+
+/// Gets the DeviceID and EventID the physical LPI `intid` is mapped from on the host ITS, and
+/// the ICID of its collection.
+pub(crate) fn host_lpi_event(intid: u32) -> Option<(u32, u32, u16)> {
+    let mappings = HOST_ITS_MAPPINGS.lock();
+    let &(_, device_id, event_id, icid) = mappings.events.get(&intid)?;
+    Some((device_id, event_id, icid))
+}
+
+/// Builds the SYNC commands that wait for the host redistributors targeted by the collections
+/// `icids` to have taken the previous commands into account.
+///
+/// Collections not mapped on behalf of a VM may target any redistributor, every host
+/// redistributor is synchronized then.
+pub(crate) fn host_sync_cmds(icids: &[u16]) -> AxResult<Vec<[u64; 4]>> {
+    let pta = read_host_gits(GITS_TYPER)? & GITS_TYPER_PTA != 0;
+    let owners = {
+        let mappings = HOST_ITS_MAPPINGS.lock();
+        icids
+            .iter()
+            .map(|icid| mappings.collections.get(icid).copied())
+            .collect::<Option<Vec<_>>>()
+    };
+    let gicrs = match owners {
+        Some(owners) => owners
+            .into_iter()
+            .filter_map(|(vm_id, vcpu_id)| vcpu_host_gicr(vm_id, vcpu_id))
+            .collect::<Vec<_>>(),
+        None => host_gicrs().iter().collect(),
+    };
+
+    let mut targets = gicrs
+        .into_iter()
+        .map(|gicr| gicr.rd_target(pta))
+        .collect::<Vec<_>>();
+    targets.sort_unstable();
+    targets.dedup();
+    Ok(targets
+        .into_iter()
+        .map(|target| [ITS_CMD_SYNC, 0, target, 0])
+        .collect())
+}
+
+/// Gets the collections mapped on the host ITS.
+pub(crate) fn host_collections() -> Vec<u16> {
+    HOST_ITS_MAPPINGS
+        .lock()
+        .collections
+        .keys()
+        .copied()
+        .collect()
+}
+
+/// Moves the host ITS collections a VM mapped to a vCPU to the host redistributor `gicr`, when
+/// the vCPU migrates.
+///
+/// LPIs already pending on the old redistributor are still delivered there.
+pub(crate) fn retarget_vcpu_collections(vm_id: VMId, vcpu_id: VCpuId, gicr: &HostGicr) -> AxResult {
+    let icids = HOST_ITS_MAPPINGS
+        .lock()
+        .collections
+        .iter()
+        .filter(|(_, &owner)| owner == (vm_id, vcpu_id))
+        .map(|(&icid, _)| icid)
+        .collect::<Vec<_>>();
+    if icids.is_empty() {
+        return Ok(());
+    }
+
+    let target = gicr.rd_target(read_host_gits(GITS_TYPER)? & GITS_TYPER_PTA != 0);
+    let mut cmds = icids
+        .into_iter()
+        .map(|icid| [ITS_CMD_MAPC, 0, 1 << 63 | target | icid as u64, 0])
+        .collect::<Vec<_>>();
+    cmds.push([ITS_CMD_SYNC, 0, target, 0]);
+    debug!(
+        "VM {vm_id} vCPU {vcpu_id} moving {} collections",
+        cmds.len() - 1
+    );
+    send_host_cmds(&cmds)
+}
+
+/// Removes every event, device and collection a VM mapped on the host ITS.
+pub(crate) fn unmap_vm(vm_id: VMId) -> AxResult {
+    let mut cmds = Vec::new();
+    let mut devices = Vec::new();
+    let itts = {
+        let mut mappings = HOST_ITS_MAPPINGS.lock();
+        mappings
+            .events
+            .retain(|_, &mut (owner, device_id, event_id, _)| {
+                if owner == vm_id {
+                    cmds.push([
+                        ITS_CMD_DISCARD | (device_id as u64) << 32,
+                        event_id as u64,
+                        0,
+                        0,
+                    ]);
+                }
+                owner != vm_id
+            });
+        mappings.devices.retain(|&device_id, &mut owner| {
+            if owner == vm_id {
+                // MAPD with V == 0.
+                cmds.push([ITS_CMD_MAPD | (device_id as u64) << 32, 0, 0, 0]);
+                devices.push(device_id);
+            }
+            owner != vm_id
+        });
+        mappings.collections.retain(|&icid, &mut (owner, _)| {
+            if owner == vm_id {
+                // MAPC with V == 0.
+                cmds.push([ITS_CMD_MAPC, 0, icid as u64, 0]);
+            }
+            owner != vm_id
+        });
+        devices
+            .iter()
+            .filter_map(|device_id| mappings.itts.remove(device_id))
+            .collect::<Vec<_>>()
+    };
+
+    debug!("VM {vm_id} unmapping {} ITS mappings", cmds.len());
+    if cmds.is_empty() {
+        return Ok(());
+    }
+    let result = send_host_cmds(&cmds);
+    // The devices are unmapped, the host ITS no longer uses their ITTs.
+    drop(itts);
+    result
+}
+
+/// ITS resources the hypervisor granted to a VM.
+#[derive(Default)]
+struct ItsGrants {
+    /// DeviceIDs the VM may map, from the DeviceID the guest uses to the physical one.
+    devices: BTreeMap<u32, u32>,
+    /// Number of commands of the VM rejected by [`Cmdq::sanitize_cmd`].
+    rejected: usize,
 }
 
-fn ring_ptr_update(val: usize) -> usize {
-    if val >= 0x10000 {
-        val - 0x10000
+static ITS_GRANTS: Mutex<BTreeMap<VMId, ItsGrants>> = Mutex::new(BTreeMap::new());
+
+/// Allows a VM to use the device `device_id` through the host ITS under its physical DeviceID,
+/// e.g. a passthrough device.
+///
+/// The commands of VMs other than the root VM may only name DeviceIDs granted to them. Fails with
+/// `AlreadyExists` if the device is granted to another VM.
+pub fn grant_its_device(vm_id: VMId, device_id: u32) -> AxResult {
+    map_its_device(vm_id, device_id, device_id)
+}
+
+/// Allows a VM to use the device with physical DeviceID `physical_id` through the host ITS, under
+/// the DeviceID `virtual_id`, e.g. the RID of a passthrough device in the guest's PCI tree.
+///
+/// The DeviceID of the VM's ITS commands is rewritten to the physical one on the way to the host
+/// ITS. EventIDs are kept, as the device signals the EventIDs the guest programmed into it.
+///
+/// Fails with `AlreadyExists` if the physical device is granted to another VM or to this VM under
+/// another DeviceID, or if the VM already uses `virtual_id` for another device.
+pub fn map_its_device(vm_id: VMId, virtual_id: u32, physical_id: u32) -> AxResult {
+    let mut grants = ITS_GRANTS.lock();
+    if grants
+        .iter()
+        .any(|(&vm, grants)| vm != vm_id && grants.devices.values().any(|&dev| dev == physical_id))
+    {
+        return ax_err!(AlreadyExists, "Device is granted to another VM");
+    }
+
+    let devices = &mut grants.entry(vm_id).or_default().devices;
+    if devices
+        .get(&virtual_id)
+        .is_some_and(|&dev| dev != physical_id)
+    {
+        return ax_err!(
+            AlreadyExists,
+            "DeviceID is used by another device of the VM"
+        );
+    }
+    if devices
+        .iter()
+        .any(|(&id, &dev)| id != virtual_id && dev == physical_id)
+    {
+        return ax_err!(
+            AlreadyExists,
+            "Device is granted to the VM under another DeviceID"
+        );
+    }
+    debug!("VM {vm_id} ITS device {virtual_id:#x} -> {physical_id:#x}");
+    devices.insert(virtual_id, physical_id);
+    Ok(())
+}
+
+/// Withdraws the device the VM knows as `device_id`, unmapping it from the host ITS if the VM
+/// mapped it and releasing the physical LPIs of its events.
+///
+/// Fails with `NotFound` if the device is not granted to the VM.
+pub fn revoke_its_device(vm_id: VMId, device_id: u32) -> AxResult {
+    let physical = ITS_GRANTS
+        .lock()
+        .get_mut(&vm_id)
+        .and_then(|grants| grants.devices.remove(&device_id));
+    let Some(device_id) = physical else {
+        return ax_err!(NotFound, "Device is not granted to the VM");
+    };
+
+    let itt = {
+        let mut mappings = HOST_ITS_MAPPINGS.lock();
+        if mappings.devices.get(&device_id) != Some(&vm_id) {
+            return Ok(());
+        }
+        mappings.devices.remove(&device_id);
+        mappings.itts.remove(&device_id)
+    };
+    // MAPD with V == 0.
+    send_host_cmds(&[[ITS_CMD_MAPD | (device_id as u64) << 32, 0, 0, 0]])?;
+    // The device is unmapped, the host ITS no longer uses its ITT.
+    drop(itt);
+    unmap_events(device_id, None);
+    Ok(())
+}
+
+/// Rewrites the DeviceID of a VM's command to the physical one, as mapped by [`map_its_device`].
+///
+/// DeviceIDs without a mapping are kept, only the root VM may use them.
+fn remap_device(vm_id: VMId, mut cmd: [u64; 4]) -> [u64; 4] {
+    let has_device = matches!(
+        cmd[0] & 0xff,
+        ITS_CMD_MAPD
+            | ITS_CMD_MAPTI
+            | ITS_CMD_MAPI
+            | ITS_CMD_MOVI
+            | ITS_CMD_DISCARD
+            | ITS_CMD_INT
+            | ITS_CMD_CLEAR
+            | ITS_CMD_INV
+    );
+    if !has_device {
+        return cmd;
+    }
+
+    let device_id = (cmd[0] >> 32) as u32;
+    let physical = ITS_GRANTS
+        .lock()
+        .get(&vm_id)
+        .and_then(|grants| grants.devices.get(&device_id).copied());
+    if let Some(physical) = physical {
+        cmd[0] = (cmd[0] & 0xffff_ffff) | (physical as u64) << 32;
+    }
+    cmd
+}
+
+/// Gets the number of ITS commands of a VM rejected for touching resources it does not own.
+pub fn rejected_its_cmds(vm_id: VMId) -> usize {
+    ITS_GRANTS
+        .lock()
+        .get(&vm_id)
+        .map_or(0, |grants| grants.rejected)
+}
+
+/// Forgets the ITS resources granted to a VM.
+pub(crate) fn revoke_vm_grants(vm_id: VMId) {
+    ITS_GRANTS.lock().remove(&vm_id);
+}
+
+static CMDQ: Mutex<Option<Cmdq>> = Mutex::new(None);
+
+/// Sets up the host ITS command queue and tables, if not done yet.
+pub(crate) fn init_cmdq(host_gits_base: HostPhysAddr) {
+    let mut cmdq = CMDQ.lock();
+    if cmdq.is_none() {
+        *cmdq = Some(Cmdq::new(host_gits_base));
+    }
+}
+
+/// Disables the host ITS and frees its command queue, its tables and the ITTs allocated for VMs.
+pub(crate) fn reset_cmdq() {
+    CMDQ.lock().take();
+    *HOST_ITS_MAPPINGS.lock() = HostItsMappings::default();
+}
+
+/// Wraps an offset advanced past the end of a ring of `size` bytes.
+pub(crate) fn ring_ptr_update(val: usize, size: usize) -> usize {
+    if val >= size {
+        val - size
     } else {
         val
     }
 }
+
+/// Number of bytes from `readr` to `writer` in a ring of `size` bytes.
+fn ring_distance(readr: usize, writer: usize, size: usize) -> usize {
+    if writer >= readr {
+        writer - readr
+    } else {
+        size - readr + writer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A one-page command queue.
+    const SIZE: usize = 0x1000;
+
+    #[test]
+    fn ring_ptr_update_wraps() {
+        assert_eq!(ring_ptr_update(0, SIZE), 0);
+        assert_eq!(
+            ring_ptr_update(SIZE - BYTES_PER_CMD, SIZE),
+            SIZE - BYTES_PER_CMD
+        );
+        assert_eq!(ring_ptr_update(SIZE, SIZE), 0);
+        assert_eq!(ring_ptr_update(SIZE + BYTES_PER_CMD, SIZE), BYTES_PER_CMD);
+    }
+
+    #[test]
+    fn ring_distance_writer_behind_readr() {
+        // The writer wrapped around while the reader has not.
+        let readr = SIZE - 2 * BYTES_PER_CMD;
+        assert_eq!(ring_distance(readr, BYTES_PER_CMD, SIZE), 3 * BYTES_PER_CMD);
+        assert_eq!(ring_distance(readr, 0, SIZE), 2 * BYTES_PER_CMD);
+    }
+
+    #[test]
+    fn ring_distance_empty() {
+        assert_eq!(ring_distance(0, 0, SIZE), 0);
+        assert_eq!(ring_distance(SIZE / 2, SIZE / 2, SIZE), 0);
+    }
+
+    #[test]
+    fn ring_distance_full() {
+        // A full ring keeps one command free, the writer is just behind the reader.
+        assert_eq!(
+            ring_distance(0, SIZE - BYTES_PER_CMD, SIZE),
+            SIZE - BYTES_PER_CMD
+        );
+        let readr = 4 * BYTES_PER_CMD;
+        assert_eq!(
+            ring_distance(readr, readr - BYTES_PER_CMD, SIZE),
+            SIZE - BYTES_PER_CMD
+        );
+    }
+}
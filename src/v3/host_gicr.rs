@@ -0,0 +1,215 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate alloc;
+
+use alloc::{collections::BTreeMap, vec::Vec};
+
+use axaddrspace::{device::AccessWidth, GuestPhysAddr, HostPhysAddr};
+use axvisor_api::vmm::{VCpuId, VMId};
+use log::{debug, warn};
+use spin::{Mutex, Once};
+
+use super::{registers::*, utils::perform_mmio_read};
+
+/// Size of a GICv3 redistributor: the RD_base and SGI_base frames.
+const GICR_STRIDE_V3: usize = 0x20000;
+/// Size of a GICv4 redistributor, which adds the VLPI_base and a reserved frame.
+const GICR_STRIDE_V4: usize = 0x40000;
+
+/// A host redistributor.
+#[derive(Clone, Copy, Debug)]
+pub struct HostGicr {
+    /// GICR_TYPER.Affinity_Value, in Aff3.Aff2.Aff1.Aff0 format.
+    pub affinity: u32,
+    /// GICR_TYPER.Processor_Number.
+    pub processor_number: usize,
+    /// Host physical address of the RD_base frame.
+    pub base: HostPhysAddr,
+    /// Whether the redistributor supports virtual LPIs (GICR_TYPER.VLPIS).
+    pub vlpis: bool,
+    /// Whether the redistributor supports GICR_SETLPIR, GICR_INVLPIR and friends
+    /// (GICR_TYPER.DirectLPI).
+    pub direct_lpi: bool,
+    /// Whether the redistributor implements the GICv4.1 vPE interface (GICR_TYPER.RVPEID).
+    pub rvpeid: bool,
+}
+
+impl HostGicr {
+    /// Encodes the redistributor as the RDbase field of an ITS command, by physical address if
+    /// the host ITS has GITS_TYPER.PTA set and by processor number otherwise.
+    pub fn rd_target(&self, pta: bool) -> u64 {
+        let target = if pta {
+            self.base.as_usize() >> 16
+        } else {
+            self.processor_number
+        };
+        (target as u64) << 16
+    }
+}
+
+static HOST_GICRS: Once<Vec<HostGicr>> = Once::new();
+
+/// The redistributors of a vCPU.
+#[derive(Clone, Copy)]
+struct VCpuGicr {
+    /// Guest physical address of the RD_base frame of the vCPU's VGicR.
+    guest_base: GuestPhysAddr,
+    /// Affinity of the virtual MPIDR_EL1 of the vCPU, in the GICR_TYPER format.
+    affinity: u32,
+    /// The host redistributor the vCPU is currently bound to.
+    host: &'static HostGicr,
+}
+
+/// The redistributors of each vCPU.
+static VCPU_GICRS: Mutex<BTreeMap<(VMId, VCpuId), VCpuGicr>> = Mutex::new(BTreeMap::new());
+
+/// Discovers the host redistributors in the given redistributor regions.
+///
+/// Each region is walked until a redistributor with GICR_TYPER.Last set. Only the first call has
+/// effect, it must happen before any VGicR is created on systems with more than one region.
+pub fn init_host_gicrs(regions: &[HostPhysAddr]) -> &'static [HostGicr] {
+    HOST_GICRS.call_once(|| {
+        let mut gicrs = Vec::new();
+        for &region in regions {
+            discover_region(region, &mut gicrs);
+        }
+        gicrs
+    })
+}
+
+/// Gets the host redistributors.
+///
+/// If [`init_host_gicrs`] has not been called, the single region at the host GICR base is
+/// discovered.
+pub fn host_gicrs() -> &'static [HostGicr] {
+    init_host_gicrs(&[crate::api_reexp::get_host_gicr_base()])
+}
+
+/// Finds the host redistributor of the physical CPU with the given MPIDR_EL1 value.
+pub fn find_host_gicr(mpidr: u64) -> Option<&'static HostGicr> {
+    let affinity = mpidr_to_affinity(mpidr);
+    host_gicrs().iter().find(|gicr| gicr.affinity == affinity)
+}
+
+/// Records the VGicR of a vCPU at `guest_base`, the virtual MPIDR_EL1 of the vCPU and the host
+/// redistributor it is bound to, when the VGicR is created or migrated.
+pub(crate) fn bind_vcpu_gicr(
+    vm_id: VMId,
+    vcpu_id: VCpuId,
+    guest_base: GuestPhysAddr,
+    vcpu_mpidr: u64,
+    host: &'static HostGicr,
+) {
+    VCPU_GICRS.lock().insert(
+        (vm_id, vcpu_id),
+        VCpuGicr {
+            guest_base,
+            affinity: mpidr_to_affinity(vcpu_mpidr),
+            host,
+        },
+    );
+}
+
+/// Forgets the host redistributors of all vCPUs of a VM.
+pub(crate) fn unbind_vm_gicrs(vm_id: VMId) {
+    VCPU_GICRS.lock().retain(|&(vm, _), _| vm != vm_id);
+}
+
+/// Gets the host redistributor a vCPU is bound to.
+pub(crate) fn vcpu_host_gicr(vm_id: VMId, vcpu_id: VCpuId) -> Option<&'static HostGicr> {
+    Some(VCPU_GICRS.lock().get(&(vm_id, vcpu_id))?.host)
+}
+
+/// Finds the vCPU of a VM whose virtual MPIDR_EL1 has the given affinity, in the GICR_TYPER
+/// format.
+pub(crate) fn affinity_to_vcpu(vm_id: VMId, affinity: u32) -> Option<VCpuId> {
+    VCPU_GICRS
+        .lock()
+        .range((vm_id, 0)..=(vm_id, VCpuId::MAX))
+        .find(|(_, gicr)| gicr.affinity == affinity)
+        .map(|(&(_, vcpu_id), _)| vcpu_id)
+}
+
+/// Finds the vCPU of a VM whose redistributor the guest names with the RDbase field `rd_base` of
+/// an ITS command: the guest physical address of its RD_base frame if `pta` is set, its
+/// processor number, i.e. the vCPU ID, otherwise.
+pub(crate) fn rd_base_to_vcpu(vm_id: VMId, rd_base: u64, pta: bool) -> Option<VCpuId> {
+    let target = (rd_base >> 16) as usize;
+    let gicrs = VCPU_GICRS.lock();
+    let mut vcpus = gicrs.range((vm_id, 0)..=(vm_id, VCpuId::MAX));
+    if pta {
+        vcpus
+            .find(|(_, gicr)| gicr.guest_base.as_usize() >> 16 == target)
+            .map(|(&(_, vcpu_id), _)| vcpu_id)
+    } else {
+        vcpus
+            .find(|(&(_, vcpu_id), _)| vcpu_id == target)
+            .map(|(&(_, vcpu_id), _)| vcpu_id)
+    }
+}
+
+/// Finds the vCPU of a VM currently bound to the host redistributor named by the RDbase field
+/// `rd_base` of a host ITS command, encoded as by [`HostGicr::rd_target`].
+pub(crate) fn host_rd_base_to_vcpu(vm_id: VMId, rd_base: u64, pta: bool) -> Option<VCpuId> {
+    VCPU_GICRS
+        .lock()
+        .range((vm_id, 0)..=(vm_id, VCpuId::MAX))
+        .find(|(_, gicr)| gicr.host.rd_target(pta) == rd_base)
+        .map(|(&(_, vcpu_id), _)| vcpu_id)
+}
+
+/// Converts an MPIDR_EL1 value to the Aff3.Aff2.Aff1.Aff0 format used by GICR_TYPER.
+pub fn mpidr_to_affinity(mpidr: u64) -> u32 {
+    // Aff3 lives in MPIDR_EL1[39:32], Aff2:Aff1:Aff0 in MPIDR_EL1[23:0].
+    (((mpidr >> 8) & 0xff00_0000) | (mpidr & 0xff_ffff)) as u32
+}
+
+fn discover_region(region: HostPhysAddr, gicrs: &mut Vec<HostGicr>) {
+    let mut base = region;
+    loop {
+        let Ok(pidr2) = perform_mmio_read(base + GICR_PIDR2, AccessWidth::Dword) else {
+            break;
+        };
+        // GICR_PIDR2.ArchRev must be GICv3 or GICv4.
+        let arch_rev = (pidr2 >> 4) & 0xf;
+        if arch_rev != 3 && arch_rev != 4 {
+            warn!("No redistributor at {base:#x} (PIDR2 {pidr2:#x}), stopping region walk");
+            break;
+        }
+
+        let Ok(typer) = perform_mmio_read(base + GICR_TYPER, AccessWidth::Qword) else {
+            break;
+        };
+        let gicr = HostGicr {
+            affinity: (typer >> GICR_TYPER_AFFINITY_SHIFT) as u32,
+            processor_number: (typer >> GICR_TYPER_PROCESSOR_NUMBER_SHIFT) & 0xffff,
+            base,
+            vlpis: typer & GICR_TYPER_VLPIS != 0,
+            direct_lpi: typer & GICR_TYPER_DIRECT_LPI != 0,
+            rvpeid: typer & GICR_TYPER_RVPEID != 0,
+        };
+        debug!("Found host redistributor {gicr:x?}");
+        gicrs.push(gicr);
+
+        if typer & GICR_TYPER_LAST != 0 {
+            break;
+        }
+        base += if gicr.vlpis {
+            GICR_STRIDE_V4
+        } else {
+            GICR_STRIDE_V3
+        };
+    }
+}
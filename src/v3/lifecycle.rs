@@ -0,0 +1,114 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The host LPI property table and ITS command queue are shared by all VMs. They are created with
+//! the first virtual ITS, VMs attach to them when their ITS is created and must be detached by
+//! the hypervisor when they are destroyed, and they can be reset once no VM is attached anymore.
+//!
+//! EnableLPIs is never cleared on the host redistributors: it may not be clearable on every
+//! implementation, and the redistributors keep using GICR_PROPBASER and GICR_PENDBASER while it
+//! is set. The LPI tables are therefore allocated once and kept across resets, only the LPI
+//! allocator, the host ITS mappings and the command queue are re-initialized.
+
+extern crate alloc;
+
+use alloc::collections::BTreeSet;
+
+use axaddrspace::HostPhysAddr;
+use axerrno::{ax_err, AxResult};
+use axvisor_api::vmm::VMId;
+use log::{debug, warn};
+use spin::Mutex;
+
+use super::{
+    gits::{init_cmdq, reset_cmdq, revoke_vm_grants, unmap_vm},
+    host_gicr::unbind_vm_gicrs,
+    lpi::release_vm_lpis,
+    lpi_alloc::{lpi_allocator, LpiAllocator},
+    vgicr::{init_lpt, reset_lpt, LPT},
+    vpe::destroy_vm_vpes,
+};
+
+/// VMs using the host LPI and ITS resources.
+static ATTACHED_VMS: Mutex<BTreeSet<VMId>> = Mutex::new(BTreeSet::new());
+
+/// Creates the host LPI property table and the host ITS command queue at `host_gits_base`, if
+/// not done yet.
+pub fn create(host_gits_base: HostPhysAddr) {
+    init_lpt(crate::api_reexp::read_vgicd_typer());
+    init_cmdq(host_gits_base);
+}
+
+/// Attaches a VM to the host LPI and ITS resources. Attaching a VM twice has no effect.
+///
+/// Fails with `BadState` if they have not been created.
+pub fn attach_vm(vm_id: VMId) -> AxResult {
+    if LPT.read().is_none() {
+        return ax_err!(BadState, "Host LPI resources are not created");
+    }
+    if ATTACHED_VMS.lock().insert(vm_id) {
+        debug!("VM {vm_id} attached to the host ITS");
+    }
+    Ok(())
+}
+
+/// Detaches a VM from the host LPI and ITS resources, e.g. when the VM is destroyed.
+///
+/// The events, devices and collections the VM mapped on the host ITS are unmapped, its vPEs are
+/// unmapped and freed, its physical LPIs are disabled and returned to the allocator, and the
+/// devices granted to it are withdrawn. All steps are attempted even if some fail, the first
+/// error is returned.
+///
+/// The vCPUs of the VM must not be running.
+pub fn detach_vm(vm_id: VMId) -> AxResult {
+    if !ATTACHED_VMS.lock().remove(&vm_id) {
+        return ax_err!(NotFound, "VM is not attached to the host ITS");
+    }
+    debug!("Detaching VM {vm_id} from the host ITS");
+
+    let mut result = Ok(());
+    for (step, res) in [
+        ("unmap ITS mappings", unmap_vm(vm_id)),
+        ("destroy vPEs", destroy_vm_vpes(vm_id)),
+        ("release LPIs", release_vm_lpis(vm_id)),
+    ] {
+        if let Err(err) = res {
+            warn!("VM {vm_id} failed to {step}: {err:?}");
+            result = result.and(Err(err));
+        }
+    }
+    revoke_vm_grants(vm_id);
+    unbind_vm_gicrs(vm_id);
+    result
+}
+
+/// Resets the host LPI and ITS resources, so that [`create`] sets up a fresh command queue.
+///
+/// Every LPI is disabled in the host LPI property table, which is kept along with the pending
+/// tables, then the command queue is freed and the host ITS mappings and the LPI allocator are
+/// re-initialized.
+///
+/// Fails with `ResourceBusy` if any VM is still attached.
+pub fn reset() -> AxResult {
+    if !ATTACHED_VMS.lock().is_empty() {
+        return ax_err!(ResourceBusy, "VMs are still attached to the host ITS");
+    }
+    debug!("Resetting the host ITS and LPI resources");
+
+    // Disabling the LPIs may send INVALL on the host ITS, so do it before dropping the queue.
+    let result = reset_lpt();
+    reset_cmdq();
+    *lpi_allocator().lock() = LpiAllocator::from_gicd_typer(crate::api_reexp::read_vgicd_typer());
+    result
+}
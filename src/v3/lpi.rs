@@ -0,0 +1,624 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate alloc;
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    vec::Vec,
+};
+
+use core::ptr;
+
+use axaddrspace::{device::AccessWidth, GuestPhysAddr, HostPhysAddr};
+use axerrno::{ax_err, AxResult};
+use axvisor_api::{
+    memory::phys_to_virt,
+    vmm::{VCpuId, VMId},
+};
+use log::{debug, warn};
+use spin::Mutex;
+
+use super::{
+    api,
+    lpi_alloc::lpi_allocator,
+    registers::*,
+    utils::{perform_mmio_read, perform_mmio_write, read_guest, write_guest},
+    vgicr::{set_lpi_prop, with_lpt, LpiConfig},
+    vpe,
+};
+
+/// LPI state of a VM, shared by its redistributors and its ITS.
+#[derive(Default)]
+struct VmLpis {
+    /// GICR_PROPBASER as last programmed by the guest.
+    ///
+    /// Guests program the same value on every redistributor, the last write wins.
+    propbaser: usize,
+    /// LPIs owned by the VM, from the guest's INTID to the physical LPI backing it.
+    ///
+    /// LPIs of VMs with vPEs are virtual LPIs of the vPEs and have no physical LPI.
+    lpis: BTreeMap<u32, Option<u32>>,
+    /// Purely virtual LPI state of the VM's vCPUs.
+    vcpus: BTreeMap<VCpuId, VCpuLpis>,
+}
+
+/// Virtual LPI state of a vCPU, for LPIs that no physical LPI or vPE delivers, e.g. those made
+/// pending through GICR_SETLPIR or a software ITS.
+#[derive(Default)]
+struct VCpuLpis {
+    /// Whether the guest has set GICR_CTLR.EnableLPIs.
+    enabled: bool,
+    /// Pending LPIs that have not been handed to a list register yet.
+    pending: BTreeSet<u32>,
+    /// LPIs whose pending state [`load_pending`] handed to the host redistributor.
+    loaded: BTreeSet<u32>,
+}
+
+impl VmLpis {
+    /// Checks if `intid` is in the VM's LPI space, see [`in_lpi_space`].
+    fn in_lpi_space(&self, intid: u32) -> bool {
+        let id_bits = (self.propbaser & GICR_PROPBASER_IDBITS_MASK) + 1;
+        intid >= LPI_BASE && (self.propbaser == 0 || (intid as usize) >> id_bits == 0)
+    }
+
+    /// Reads the property byte of `intid` from the guest's LPI configuration table.
+    ///
+    /// LPIs beyond GICR_PROPBASER.IDbits, or without a table, read as disabled.
+    fn guest_prop(&self, vm_id: VMId, intid: u32) -> AxResult<u8> {
+        read_guest_prop(vm_id, self.propbaser, intid)
+    }
+
+    /// Gives the LPI `intid` to the VM, backed by `physical` if any.
+    fn insert_lpi(&mut self, vm_id: VMId, intid: u32, physical: Option<u32>) {
+        self.lpis.insert(intid, physical);
+        if let Some(physical) = physical {
+            PHYSICAL_LPIS.lock().insert(physical, (vm_id, intid));
+        }
+    }
+
+    /// Takes the LPI `intid` back from the VM.
+    fn remove_lpi(&mut self, intid: u32) {
+        if let Some(Some(physical)) = self.lpis.remove(&intid) {
+            PHYSICAL_LPIS.lock().remove(&physical);
+        }
+    }
+
+    /// Takes the pending virtual LPIs of a vCPU out for [`deliver_pending`], if the vCPU has
+    /// enabled LPIs.
+    fn take_pending(&mut self, vcpu_id: VCpuId) -> Option<TakenLpis> {
+        let vcpu = self.vcpus.get_mut(&vcpu_id)?;
+        if !vcpu.enabled || vcpu.pending.is_empty() {
+            return None;
+        }
+        Some(TakenLpis {
+            propbaser: self.propbaser,
+            pending: core::mem::take(&mut vcpu.pending),
+        })
+    }
+
+    /// Applies the guest's property of `intid` to the LPI backing it.
+    fn sync(&self, vm_id: VMId, intid: u32, physical: Option<u32>) -> AxResult {
+        let prop = self.guest_prop(vm_id, intid)?;
+        debug!("VM {vm_id} LPI {intid} ({physical:?}) property {prop:#x}");
+        let prop = (prop & (LPI_PROP_PRIORITY_MASK | LPI_PROP_ENABLE)) | LPI_PROP_RES1;
+        match physical {
+            Some(physical) => {
+                set_lpi_prop((physical - LPI_BASE) as usize, prop);
+                Ok(())
+            }
+            None => vpe::set_vlpi_prop(vm_id, intid, prop),
+        }
+    }
+}
+
+/// Pending virtual LPIs of a vCPU taken out of [`VM_LPIS`], to be injected without the lock held.
+struct TakenLpis {
+    /// GICR_PROPBASER of the VM when the LPIs were taken.
+    propbaser: usize,
+    pending: BTreeSet<u32>,
+}
+
+/// Reads the property byte of `intid` from the LPI configuration table at `propbaser`.
+///
+/// LPIs beyond GICR_PROPBASER.IDbits, or without a table, read as disabled.
+fn read_guest_prop(vm_id: VMId, propbaser: usize, intid: u32) -> AxResult<u8> {
+    let table = propbaser & GICR_BASER_PA_MASK;
+    let id_bits = (propbaser & GICR_PROPBASER_IDBITS_MASK) + 1;
+    if table == 0 || (intid as usize) >> id_bits != 0 {
+        return Ok(0);
+    }
+
+    read_guest(
+        vm_id,
+        GuestPhysAddr::from_usize(table + (intid - LPI_BASE) as usize),
+    )
+}
+
+/// Injects the LPIs taken by [`VmLpis::take_pending`] that the guest has enabled, and puts the
+/// others back. Must be called without the [`VM_LPIS`] lock, or any lock of an emulated device,
+/// held.
+fn deliver_pending(vm_id: VMId, vcpu_id: VCpuId, taken: Option<TakenLpis>) {
+    let Some(taken) = taken else {
+        return;
+    };
+    let kept = taken
+        .pending
+        .into_iter()
+        .filter(
+            |&intid| match read_guest_prop(vm_id, taken.propbaser, intid) {
+                Ok(prop) if prop & LPI_PROP_ENABLE != 0 => {
+                    api::inject_virtual_interrupt(vm_id, vcpu_id, intid);
+                    false
+                }
+                Ok(_) => true,
+                Err(err) => {
+                    warn!("Failed to read the property of virtual LPI {intid}: {err:?}");
+                    true
+                }
+            },
+        )
+        .collect::<BTreeSet<_>>();
+    if kept.is_empty() {
+        return;
+    }
+    let mut vms = VM_LPIS.lock();
+    let vm = vms.entry(vm_id).or_default();
+    vm.vcpus.entry(vcpu_id).or_default().pending.extend(kept);
+}
+
+/// LPI state of all VMs.
+static VM_LPIS: Mutex<BTreeMap<VMId, VmLpis>> = Mutex::new(BTreeMap::new());
+/// Physical LPIs backing LPIs of VMs, to the VM and the guest's INTID. Kept in step with
+/// [`VmLpis::lpis`], and only locked with [`VM_LPIS`] held or on its own.
+static PHYSICAL_LPIS: Mutex<BTreeMap<u32, (VMId, u32)>> = Mutex::new(BTreeMap::new());
+
+/// Records the GICR_PROPBASER value programmed by a VM.
+pub fn set_propbaser(vm_id: VMId, propbaser: usize) {
+    VM_LPIS.lock().entry(vm_id).or_default().propbaser = propbaser;
+}
+
+/// Reads the property byte of the LPI `intid` from the LPI configuration table of a VM.
+pub fn guest_lpi_prop(vm_id: VMId, intid: u32) -> AxResult<u8> {
+    if intid < LPI_BASE {
+        return ax_err!(InvalidInput, "INTID is not an LPI");
+    }
+
+    match VM_LPIS.lock().get(&vm_id) {
+        Some(vm) => vm.guest_prop(vm_id, intid),
+        None => Ok(0),
+    }
+}
+
+/// Checks if `intid` is in the LPI space of a VM, as sized by its GICR_PROPBASER.IDbits.
+///
+/// Any LPI is accepted before the VM has programmed GICR_PROPBASER.
+pub fn in_lpi_space(vm_id: VMId, intid: u32) -> bool {
+    match VM_LPIS.lock().get(&vm_id) {
+        Some(vm) => vm.in_lpi_space(intid),
+        None => intid >= LPI_BASE,
+    }
+}
+
+/// Gives the LPI `intid` of a VM's LPI space to the VM, and applies the VM's property for it.
+///
+/// A physical LPI is allocated to back it, unless the VM has vPEs. Returns the INTID to use on
+/// the host: the physical LPI, or `intid` itself for a virtual LPI of a vPE.
+pub fn claim_lpi(vm_id: VMId, intid: u32) -> AxResult<u32> {
+    if intid < LPI_BASE {
+        return ax_err!(InvalidInput, "INTID is not an LPI");
+    }
+
+    let mut vms = VM_LPIS.lock();
+    let vm = vms.entry(vm_id).or_default();
+    let physical = match vm.lpis.get(&intid) {
+        Some(&physical) => physical,
+        None => {
+            let physical = if vpe::has_vpes(vm_id) {
+                None
+            } else {
+                Some(lpi_allocator().lock().alloc(vm_id)?)
+            };
+            debug!("VM {vm_id} LPI {intid} backed by {physical:?}");
+            vm.insert_lpi(vm_id, intid, physical);
+            physical
+        }
+    };
+    vm.sync(vm_id, intid, physical)?;
+    Ok(physical.unwrap_or(intid))
+}
+
+/// Gives the `count` LPIs from `first` of a VM's LPI space to the VM, backed by a contiguous
+/// block of physical LPIs, e.g. for the MSI-X vectors of a passthrough device.
+///
+/// The physical block is aligned to its size rounded up to a power of two, as multi-MSI needs.
+/// None of the LPIs may be owned by the VM yet. Returns the first physical LPI.
+pub fn claim_lpi_range(vm_id: VMId, first: u32, count: usize) -> AxResult<u32> {
+    if first < LPI_BASE || count == 0 {
+        return ax_err!(InvalidInput, "Invalid LPI range");
+    }
+    if vpe::has_vpes(vm_id) {
+        return ax_err!(
+            Unsupported,
+            "LPIs of VMs with vPEs are not backed by physical LPIs"
+        );
+    }
+
+    let mut vms = VM_LPIS.lock();
+    let vm = vms.entry(vm_id).or_default();
+    let range = first..first + count as u32;
+    if vm.lpis.range(range.clone()).next().is_some() {
+        return ax_err!(AlreadyExists, "LPI is already owned by the VM");
+    }
+
+    let physical = lpi_allocator()
+        .lock()
+        .alloc_range(vm_id, count, count.next_power_of_two())?;
+    for (intid, backing) in range.clone().zip(physical..) {
+        vm.insert_lpi(vm_id, intid, Some(backing));
+        if let Err(err) = vm.sync(vm_id, intid, Some(backing)) {
+            // Give the whole block back, the caller cannot use part of it.
+            for (intid, backing) in range.zip(physical..) {
+                vm.remove_lpi(intid);
+                set_lpi_prop((backing - LPI_BASE) as usize, LPI_PROP_RES1);
+            }
+            if let Err(err) = lpi_allocator().lock().free_range(vm_id, physical, count) {
+                warn!("VM {vm_id} failed to free LPIs {physical}..+{count}: {err:?}");
+            }
+            return Err(err);
+        }
+    }
+    Ok(physical)
+}
+
+/// Gets the physical LPI backing the LPI `intid` of a VM.
+pub fn physical_lpi(vm_id: VMId, intid: u32) -> Option<u32> {
+    *VM_LPIS.lock().get(&vm_id)?.lpis.get(&intid)?
+}
+
+/// Gets the VM and the guest's INTID the physical LPI `physical` backs, e.g. to inject a
+/// physical LPI the hypervisor took for a passthrough MSI.
+pub fn virtual_lpi(physical: u32) -> Option<(VMId, u32)> {
+    PHYSICAL_LPIS.lock().get(&physical).copied()
+}
+
+/// Applies the guest's property of the LPI `intid` to the LPI backing it.
+///
+/// Returns the physical LPI, if any. Fails with `InvalidInput` if `intid` is not an LPI, and with
+/// `PermissionDenied` if the VM does not own the LPI.
+pub fn sync_lpi(vm_id: VMId, intid: u32) -> AxResult<Option<u32>> {
+    if intid < LPI_BASE {
+        return ax_err!(InvalidInput, "INTID is not an LPI");
+    }
+
+    let vms = VM_LPIS.lock();
+    let Some((vm, &physical)) = vms
+        .get(&vm_id)
+        .and_then(|vm| Some((vm, vm.lpis.get(&intid)?)))
+    else {
+        return ax_err!(PermissionDenied, "LPI is not owned by the VM");
+    };
+    vm.sync(vm_id, intid, physical)?;
+    Ok(physical)
+}
+
+/// Applies the guest's properties of all LPIs owned by a VM.
+///
+/// All LPIs are synchronized even if some fail, the first error is returned.
+pub fn sync_vm_lpis(vm_id: VMId) -> AxResult {
+    let vms = VM_LPIS.lock();
+    let Some(vm) = vms.get(&vm_id) else {
+        return Ok(());
+    };
+
+    let mut result = Ok(());
+    for (&intid, &physical) in &vm.lpis {
+        if let Err(err) = vm.sync(vm_id, intid, physical) {
+            warn!("Failed to sync VM {vm_id} LPI {intid}: {err:?}");
+            result = result.and(Err(err));
+        }
+    }
+    result
+}
+
+/// Records whether a vCPU has enabled LPIs, and injects its pending virtual LPIs once it has.
+pub fn set_virtual_lpis_enabled(vm_id: VMId, vcpu_id: VCpuId, enabled: bool) {
+    let taken = {
+        let mut vms = VM_LPIS.lock();
+        let vm = vms.entry(vm_id).or_default();
+        vm.vcpus.entry(vcpu_id).or_default().enabled = enabled;
+        vm.take_pending(vcpu_id)
+    };
+    deliver_pending(vm_id, vcpu_id, taken);
+}
+
+/// Makes a virtual LPI pending on a vCPU.
+///
+/// The LPI is injected as soon as it is enabled in the guest's LPI configuration table and the
+/// vCPU has enabled LPIs. Fails with `BadState` if the VM has not programmed GICR_PROPBASER yet,
+/// and with `InvalidInput` if `intid` is not in its LPI space, which bounds the pending state
+/// a guest can make the hypervisor hold.
+pub fn set_virtual_pending(vm_id: VMId, vcpu_id: VCpuId, intid: u32) -> AxResult {
+    mark_virtual_pending(vm_id, vcpu_id, intid)?;
+    flush_virtual_pending(vm_id, vcpu_id);
+    Ok(())
+}
+
+/// Makes a virtual LPI pending on a vCPU like [`set_virtual_pending`], without injecting it.
+///
+/// For callers holding a lock of their own, which call [`flush_virtual_pending`] once they have
+/// dropped it.
+pub(crate) fn mark_virtual_pending(vm_id: VMId, vcpu_id: VCpuId, intid: u32) -> AxResult {
+    let mut vms = VM_LPIS.lock();
+    let vm = vms.entry(vm_id).or_default();
+    if vm.propbaser & GICR_BASER_PA_MASK == 0 {
+        return ax_err!(BadState, "LPI configuration table is not set");
+    }
+    if !vm.in_lpi_space(intid) {
+        return ax_err!(InvalidInput, "INTID is not in the LPI space of the VM");
+    }
+    vm.vcpus.entry(vcpu_id).or_default().pending.insert(intid);
+    Ok(())
+}
+
+/// Clears the pending state of a virtual LPI on a vCPU, returning whether it was pending.
+///
+/// LPIs already handed to a list register are not affected.
+pub fn clear_virtual_pending(vm_id: VMId, vcpu_id: VCpuId, intid: u32) -> bool {
+    VM_LPIS
+        .lock()
+        .get_mut(&vm_id)
+        .and_then(|vm| vm.vcpus.get_mut(&vcpu_id))
+        .is_some_and(|vcpu| vcpu.pending.remove(&intid))
+}
+
+/// Checks if a virtual LPI is pending on a vCPU.
+pub fn is_virtual_pending(vm_id: VMId, vcpu_id: VCpuId, intid: u32) -> bool {
+    VM_LPIS
+        .lock()
+        .get(&vm_id)
+        .and_then(|vm| vm.vcpus.get(&vcpu_id))
+        .is_some_and(|vcpu| vcpu.pending.contains(&intid))
+}
+
+/// Injects the pending virtual LPIs of a vCPU that the guest has enabled, e.g. after the guest
+/// changed its LPI configuration table.
+pub fn flush_virtual_pending(vm_id: VMId, vcpu_id: VCpuId) {
+    let taken = VM_LPIS
+        .lock()
+        .get_mut(&vm_id)
+        .and_then(|vm| vm.take_pending(vcpu_id));
+    deliver_pending(vm_id, vcpu_id, taken);
+}
+
+/// Moves the pending state of the virtual LPIs in `intids` from one vCPU to another, or of all
+/// its pending LPIs if `intids` is `None`, and injects those the target vCPU can take.
+pub fn move_virtual_pending(vm_id: VMId, from: VCpuId, to: VCpuId, intids: Option<&[u32]>) {
+    if shift_virtual_pending(vm_id, from, to, intids) {
+        flush_virtual_pending(vm_id, to);
+    }
+}
+
+/// Moves pending virtual LPIs like [`move_virtual_pending`], without injecting them. Returns
+/// whether any LPI was moved.
+pub(crate) fn shift_virtual_pending(
+    vm_id: VMId,
+    from: VCpuId,
+    to: VCpuId,
+    intids: Option<&[u32]>,
+) -> bool {
+    if from == to {
+        return false;
+    }
+
+    let mut vms = VM_LPIS.lock();
+    let Some(vm) = vms.get_mut(&vm_id) else {
+        return false;
+    };
+    let Some(source) = vm.vcpus.get_mut(&from) else {
+        return false;
+    };
+    let moved = match intids {
+        Some(intids) => intids
+            .iter()
+            .filter(|intid| source.pending.remove(intid))
+            .copied()
+            .collect::<BTreeSet<_>>(),
+        None => core::mem::take(&mut source.pending),
+    };
+    if moved.is_empty() {
+        return false;
+    }
+    vm.vcpus.entry(to).or_default().pending.extend(moved);
+    true
+}
+
+/// Takes the physical LPI `physical` back from a VM once the host ITS no longer maps an event to
+/// it, e.g. after DISCARD or MAPD with V == 0.
+///
+/// The LPI is disabled in the host LPI configuration table and returned to the allocator. No
+/// invalidation is needed: with its event unmapped, nothing can make the LPI pending.
+pub fn release_lpi(vm_id: VMId, physical: u32) -> AxResult {
+    if let Some(vm) = VM_LPIS.lock().get_mut(&vm_id) {
+        let intid = vm
+            .lpis
+            .iter()
+            .find(|(_, &backing)| backing == Some(physical))
+            .map(|(&intid, _)| intid);
+        if let Some(intid) = intid {
+            vm.remove_lpi(intid);
+        }
+    }
+    set_lpi_prop((physical - LPI_BASE) as usize, LPI_PROP_RES1);
+    lpi_allocator().lock().free(vm_id, physical)
+}
+
+/// Drops the LPI state of a VM, e.g. when the VM is destroyed.
+///
+/// The physical LPIs backing its LPIs are disabled, invalidated on the host and returned to the
+/// allocator.
+pub fn release_vm_lpis(vm_id: VMId) -> AxResult {
+    {
+        let mut vms = VM_LPIS.lock();
+        vms.remove(&vm_id);
+        PHYSICAL_LPIS
+            .lock()
+            .retain(|_, &mut (owner, _)| owner != vm_id);
+    }
+
+    let physical = lpi_allocator().lock().release_vm(vm_id);
+    debug!("VM {vm_id} released {} physical LPIs", physical.len());
+    if physical.is_empty() {
+        return Ok(());
+    }
+    with_lpt(|lpt| {
+        lpt.update_lpis(physical.into_iter().map(|intid| {
+            let config = LpiConfig {
+                group: true,
+                ..Default::default()
+            };
+            (intid, config)
+        }))
+    })
+}
+
+/// Loads the pending state of a VM's LPIs from its pending table into the host redistributor at
+/// `host`, to be called when vCPU `vcpu_id` enables LPIs.
+///
+/// If the guest set GICR_PENDBASER.PTZ, its table is known to be zero and is not read. The
+/// pending state of virtual LPIs of vPEs lives in the vPE's pending table instead.
+pub fn load_pending(
+    vm_id: VMId,
+    vcpu_id: VCpuId,
+    pendbaser: usize,
+    host: HostPhysAddr,
+) -> AxResult {
+    let table = pendbaser & GICR_BASER_PA_MASK;
+    let zeroed = table == 0 || pendbaser & GICR_PENDBASER_PTZ != 0;
+
+    let mut vms = VM_LPIS.lock();
+    let Some(vm) = vms.get_mut(&vm_id) else {
+        return Ok(());
+    };
+    let mut loaded = BTreeSet::new();
+    for (&intid, &physical) in &vm.lpis {
+        let Some(physical) = physical else {
+            continue;
+        };
+        let pending = !zeroed && {
+            let byte: u8 = read_guest(vm_id, pending_byte(table, intid))?;
+            byte & pending_bit(intid) != 0
+        };
+        if pending {
+            set_host_pending(host, physical, true)?;
+            loaded.insert(intid);
+        }
+    }
+    vm.vcpus.entry(vcpu_id).or_default().loaded = loaded;
+    Ok(())
+}
+
+/// Saves the pending state of a VM's LPIs into its pending table, to be called when vCPU
+/// `vcpu_id` disables LPIs on the host redistributor at `host`.
+///
+/// The host pending table cannot be read while the shared host redistributor has LPIs enabled,
+/// so the pending state is taken from software: the LPIs pending on the vCPU, and those handed
+/// to the host by [`load_pending`] that the guest has disabled and thus could not have been
+/// delivered. The host pending bits handed over are cleared.
+pub fn save_pending(
+    vm_id: VMId,
+    vcpu_id: VCpuId,
+    pendbaser: usize,
+    host: HostPhysAddr,
+) -> AxResult {
+    let table = pendbaser & GICR_BASER_PA_MASK;
+
+    let mut vms = VM_LPIS.lock();
+    let Some(vm) = vms.get_mut(&vm_id) else {
+        return Ok(());
+    };
+    let loaded = core::mem::take(&mut vm.vcpus.entry(vcpu_id).or_default().loaded);
+    let physical_lpis = vm
+        .lpis
+        .iter()
+        .filter_map(|(&intid, &physical)| Some((intid, physical?)))
+        .collect::<Vec<_>>();
+    for (intid, physical) in physical_lpis {
+        let handed_over = loaded.contains(&intid);
+        let pending = vm.vcpus[&vcpu_id].pending.contains(&intid)
+            || (handed_over && vm.guest_prop(vm_id, intid)? & LPI_PROP_ENABLE == 0);
+        if handed_over {
+            set_host_pending(host, physical, false)?;
+        }
+        if table != 0 {
+            let gpa = pending_byte(table, intid);
+            let byte: u8 = read_guest(vm_id, gpa)?;
+            let byte = if pending {
+                byte | pending_bit(intid)
+            } else {
+                byte & !pending_bit(intid)
+            };
+            write_guest(vm_id, gpa, byte)?;
+        }
+        // The pending state now lives in the guest's table. Purely virtual LPIs have no place
+        // there and stay pending in software.
+        if let Some(vcpu) = vm.vcpus.get_mut(&vcpu_id) {
+            vcpu.pending.remove(&intid);
+        }
+    }
+    Ok(())
+}
+
+fn pending_byte(table: usize, intid: u32) -> GuestPhysAddr {
+    GuestPhysAddr::from_usize(table + intid as usize / 8)
+}
+
+fn pending_bit(intid: u32) -> u8 {
+    1 << (intid % 8)
+}
+
+/// Gets the pending table the hypervisor gave to the host redistributor at `host`.
+fn host_pending_table(host: HostPhysAddr) -> AxResult<HostPhysAddr> {
+    let pendbaser = perform_mmio_read(host + GICR_PENDBASER, AccessWidth::Qword)?;
+    Ok(HostPhysAddr::from_usize(pendbaser & GICR_BASER_PA_MASK))
+}
+
+/// Sets the pending state of a physical LPI on the host redistributor at `host`.
+///
+/// The pending table is written directly while the redistributor has LPIs disabled, afterwards
+/// GICR_SETLPIR/GICR_CLRLPIR are used, which needs GICR_TYPER.DirectLPI.
+fn set_host_pending(host: HostPhysAddr, intid: u32, pending: bool) -> AxResult {
+    let ctlr = perform_mmio_read(host + GICR_CTLR, AccessWidth::Dword)?;
+    if ctlr & GICR_CTLR_ENABLE_LPIS == 0 {
+        let addr = phys_to_virt(host_pending_table(host)? + intid as usize / 8).as_mut_ptr();
+        unsafe {
+            let byte = ptr::read_volatile(addr);
+            let byte = if pending {
+                byte | pending_bit(intid)
+            } else {
+                byte & !pending_bit(intid)
+            };
+            ptr::write_volatile(addr, byte);
+        }
+        return Ok(());
+    }
+
+    let typer = perform_mmio_read(host + GICR_TYPER, AccessWidth::Qword)?;
+    if typer & GICR_TYPER_DIRECT_LPI == 0 {
+        warn!("Cannot change the pending state of LPI {intid} without DirectLPI");
+        return ax_err!(Unsupported, "Host redistributor does not support DirectLPI");
+    }
+    let reg = if pending { GICR_SETLPIR } else { GICR_CLRLPIR };
+    perform_mmio_write(host + reg, AccessWidth::Qword, intid as usize)
+}
@@ -0,0 +1,279 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate alloc;
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    vec,
+    vec::Vec,
+};
+
+use axerrno::{ax_err, AxResult};
+use axvisor_api::vmm::VMId;
+use log::debug;
+use spin::{Mutex, Once};
+
+use super::registers::LPI_BASE;
+
+/// LPIs allocated to a VM.
+#[derive(Default)]
+struct VmLpiAccount {
+    /// The allocated physical LPIs.
+    lpis: BTreeSet<u32>,
+    /// Maximum number of LPIs the VM may hold, unlimited if `None`.
+    quota: Option<usize>,
+}
+
+/// Allocator of the host's physical LPIs.
+///
+/// Every allocated LPI is accounted to a VM, which may be limited by a quota.
+pub struct LpiAllocator {
+    /// One bit per LPI starting at LPI 8192, set if allocated.
+    used: Vec<u64>,
+    /// Number of LPIs.
+    num: usize,
+    vms: BTreeMap<VMId, VmLpiAccount>,
+}
+
+impl LpiAllocator {
+    /// Creates an allocator of the LPIs below `1 << id_bits`.
+    pub fn new(id_bits: u32) -> Self {
+        let num = (1usize << id_bits).saturating_sub(LPI_BASE as usize);
+        debug!("Creating LPI allocator: id_bits: {id_bits}, {num} LPIs");
+        Self {
+            used: vec![0; num.div_ceil(64)],
+            num,
+            vms: BTreeMap::new(),
+        }
+    }
+
+    /// Creates an allocator of the LPIs supported by a distributor, from its GICD_TYPER.IDbits.
+    pub fn from_gicd_typer(gicd_typer: u32) -> Self {
+        Self::new(((gicd_typer >> 19) & 0x1f) + 1)
+    }
+
+    /// Number of LPIs managed by the allocator.
+    pub fn capacity(&self) -> usize {
+        self.num
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        self.used[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    fn set_used(&mut self, index: usize, used: bool) {
+        if used {
+            self.used[index / 64] |= 1 << (index % 64);
+        } else {
+            self.used[index / 64] &= !(1 << (index % 64));
+        }
+    }
+
+    /// Allocates `count` contiguous LPIs to a VM, with the first one aligned to `align` LPIs,
+    /// e.g. for the MSI-X vectors of a device. Returns the first LPI.
+    ///
+    /// `align` must be a power of two. Fails with `NoMemory` if no such range is free or the VM
+    /// would exceed its quota.
+    pub fn alloc_range(&mut self, vm_id: VMId, count: usize, align: usize) -> AxResult<u32> {
+        if count == 0 || !align.is_power_of_two() {
+            return ax_err!(InvalidInput, "Invalid LPI range");
+        }
+        let account = self.vms.entry(vm_id).or_default();
+        if account
+            .quota
+            .is_some_and(|quota| account.lpis.len() + count > quota)
+        {
+            return ax_err!(NoMemory, "LPI quota of the VM exceeded");
+        }
+
+        // LPI 8192 is aligned to any block size up to 8192, so aligned indices are aligned INTIDs.
+        let mut start = 0;
+        while start + count <= self.num {
+            match (start..start + count)
+                .rev()
+                .find(|&index| self.is_used(index))
+            {
+                Some(used) => start = (used + 1).next_multiple_of(align),
+                None => break,
+            }
+        }
+        if start + count > self.num {
+            return ax_err!(NoMemory, "Out of physical LPIs");
+        }
+
+        for index in start..start + count {
+            self.set_used(index, true);
+        }
+        let first = LPI_BASE + start as u32;
+        self.vms
+            .entry(vm_id)
+            .or_default()
+            .lpis
+            .extend(first..first + count as u32);
+        debug!("Allocated LPIs {first}..+{count} to VM {vm_id}");
+        Ok(first)
+    }
+
+    /// Allocates a single LPI to a VM.
+    pub fn alloc(&mut self, vm_id: VMId) -> AxResult<u32> {
+        self.alloc_range(vm_id, 1, 1)
+    }
+
+    /// Frees `count` LPIs from `first`, which must all be allocated to the VM.
+    pub fn free_range(&mut self, vm_id: VMId, first: u32, count: usize) -> AxResult {
+        let range = first..first + count as u32;
+        let Some(account) = self.vms.get_mut(&vm_id) else {
+            return ax_err!(PermissionDenied, "LPIs are not allocated to the VM");
+        };
+        if range.clone().any(|intid| !account.lpis.contains(&intid)) {
+            return ax_err!(PermissionDenied, "LPIs are not allocated to the VM");
+        }
+
+        for intid in range.clone() {
+            account.lpis.remove(&intid);
+        }
+        for intid in range {
+            self.set_used((intid - LPI_BASE) as usize, false);
+        }
+        Ok(())
+    }
+
+    /// Frees a single LPI allocated to a VM.
+    pub fn free(&mut self, vm_id: VMId, intid: u32) -> AxResult {
+        self.free_range(vm_id, intid, 1)
+    }
+
+    /// Frees all LPIs of a VM and forgets its quota, e.g. when the VM is destroyed.
+    ///
+    /// Returns the freed LPIs.
+    pub fn release_vm(&mut self, vm_id: VMId) -> Vec<u32> {
+        let Some(account) = self.vms.remove(&vm_id) else {
+            return Vec::new();
+        };
+        for &intid in &account.lpis {
+            self.set_used((intid - LPI_BASE) as usize, false);
+        }
+        account.lpis.into_iter().collect()
+    }
+
+    /// Limits the number of LPIs a VM may hold, `None` lifts the limit.
+    ///
+    /// LPIs already allocated beyond a new quota are kept.
+    pub fn set_quota(&mut self, vm_id: VMId, quota: Option<usize>) {
+        self.vms.entry(vm_id).or_default().quota = quota;
+    }
+
+    /// Number of LPIs allocated to a VM.
+    pub fn allocated(&self, vm_id: VMId) -> usize {
+        self.vms.get(&vm_id).map_or(0, |account| account.lpis.len())
+    }
+}
+
+static LPI_ALLOCATOR: Once<Mutex<LpiAllocator>> = Once::new();
+
+/// Gets the host LPI allocator, sized from the host GICD_TYPER on first use.
+pub fn lpi_allocator() -> &'static Mutex<LpiAllocator> {
+    LPI_ALLOCATOR.call_once(|| {
+        Mutex::new(LpiAllocator::from_gicd_typer(
+            crate::api_reexp::read_vgicd_typer(),
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 16 bits of INTID: LPIs 8192..65536.
+    const ID_BITS: u32 = 16;
+
+    #[test]
+    fn alloc_free_round_trip() {
+        let mut allocator = LpiAllocator::new(ID_BITS);
+        assert_eq!(allocator.capacity(), (1 << ID_BITS) - LPI_BASE as usize);
+
+        let first = allocator.alloc(1).unwrap();
+        assert_eq!(first, LPI_BASE);
+        let second = allocator.alloc(1).unwrap();
+        assert_eq!(second, LPI_BASE + 1);
+        assert_eq!(allocator.allocated(1), 2);
+
+        allocator.free(1, first).unwrap();
+        assert_eq!(allocator.allocated(1), 1);
+        // The freed LPI is handed out again.
+        assert_eq!(allocator.alloc(2).unwrap(), first);
+
+        // LPIs can only be freed by the VM holding them.
+        assert!(allocator.free(1, first).is_err());
+        assert!(allocator.free(3, second).is_err());
+        allocator.free(2, first).unwrap();
+        allocator.free(1, second).unwrap();
+        assert_eq!(allocator.allocated(1), 0);
+        assert_eq!(allocator.allocated(2), 0);
+    }
+
+    #[test]
+    fn range_alignment() {
+        let mut allocator = LpiAllocator::new(ID_BITS);
+        allocator.alloc(1).unwrap();
+
+        let first = allocator.alloc_range(1, 5, 8).unwrap();
+        assert_eq!(first, LPI_BASE + 8);
+        let next = allocator.alloc_range(1, 32, 32).unwrap();
+        assert_eq!(next, LPI_BASE + 32);
+        // Single LPIs fill the holes the alignment left.
+        assert_eq!(allocator.alloc(1).unwrap(), LPI_BASE + 1);
+
+        assert!(allocator.alloc_range(1, 4, 3).is_err());
+        assert!(allocator.alloc_range(1, 0, 1).is_err());
+
+        allocator.free_range(1, first, 5).unwrap();
+        assert_eq!(allocator.alloc_range(2, 8, 8).unwrap(), LPI_BASE + 8);
+    }
+
+    #[test]
+    fn quota_exhaustion() {
+        let mut allocator = LpiAllocator::new(ID_BITS);
+        allocator.set_quota(1, Some(4));
+
+        allocator.alloc_range(1, 3, 1).unwrap();
+        allocator.alloc(1).unwrap();
+        assert!(allocator.alloc(1).is_err());
+        assert!(allocator.alloc_range(1, 2, 1).is_err());
+        assert_eq!(allocator.allocated(1), 4);
+        // Other VMs are not limited.
+        allocator.alloc_range(2, 16, 1).unwrap();
+
+        allocator.free(1, LPI_BASE).unwrap();
+        allocator.alloc(1).unwrap();
+
+        allocator.set_quota(1, None);
+        allocator.alloc(1).unwrap();
+        assert_eq!(allocator.release_vm(1).len(), 5);
+        assert_eq!(allocator.allocated(1), 0);
+    }
+
+    #[test]
+    fn out_of_lpis() {
+        // 14 bits of INTID: LPIs 8192..16384.
+        let mut allocator = LpiAllocator::new(14);
+        let count = allocator.capacity();
+        allocator.alloc_range(1, count, 1).unwrap();
+        assert!(allocator.alloc(2).is_err());
+
+        allocator.free(1, LPI_BASE + 100).unwrap();
+        assert_eq!(allocator.alloc(2).unwrap(), LPI_BASE + 100);
+    }
+}
@@ -12,11 +12,27 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+/// Hypervisor services required by the GICv3 emulation.
+pub mod api;
 /// GICv3 ITS (Interrupt Translation Service) implementation.
 pub mod gits;
+/// Host redistributor discovery.
+pub mod host_gicr;
+/// Lifecycle of the host LPI and ITS resources.
+pub mod lifecycle;
+/// Per-VM LPI state.
+pub mod lpi;
+/// Physical LPI allocator.
+pub mod lpi_alloc;
 mod registers;
 mod utils;
 /// GICv3 distributor implementation.
 pub mod vgicd;
 /// GICv3 redistributor implementation.
 pub mod vgicr;
+/// Software GICv3 ITS.
+pub mod vits;
+/// GICv4 vPE support.
+pub mod vpe;
+/// Virtual SPI state.
+pub mod vspi;
@@ -19,6 +19,11 @@
 /// to make the number more intuitive and easier to work with.
 pub const MAX_IRQ_V3: usize = 1024;
 
+/// First INTID of the GICv3.1 Extended SPI range.
+pub const ESPI_BASE: usize = 4096;
+/// Number of INTIDs in the GICv3.1 Extended SPI range, INTID 4096-5119.
+pub const ESPI_NUM: usize = 1024;
+
 macro_rules! register_range {
     ($reg:ident, $range:ident, $number:literal, $size:literal) => {
         pub const $range: core::ops::Range<usize> = ($reg)..($reg + $number * $size);
@@ -30,8 +35,15 @@ pub const GICD_CTLR_ARE_NS: usize = 1 << 5;
 pub const GICD_CTLR_GRP1NS_ENA: usize = 1 << 1;
 
 pub const GICD_TYPER: usize = 0x0004;
+pub const GICD_TYPER_ESPI: usize = 1 << 8;
+pub const GICD_TYPER_MBIS: usize = 1 << 16;
+pub const GICD_TYPER_ESPI_RANGE_SHIFT: usize = 27;
+pub const GICD_TYPER_ESPI_RANGE_MASK: usize = 0x1f << GICD_TYPER_ESPI_RANGE_SHIFT;
 pub const GICD_IIDR: usize = 0x0008;
 pub const GICD_TYPER2: usize = 0x000c;
+pub const GICD_SETSPI_NSR: usize = 0x0040;
+pub const GICD_CLRSPI_NSR: usize = 0x0048;
+pub const GICD_SPI_INTID_MASK: usize = 0x1fff;
 
 pub const GICD_IGROUPR: usize = 0x0080;
 pub const GICD_ISENABLER: usize = 0x0100;
@@ -50,6 +62,18 @@ pub const GICD_CPENDSGIR: usize = 0x0f10;
 pub const GICD_SPENDSGIR: usize = 0x0f20;
 pub const GICD_IROUTER: usize = 0x6000;
 
+pub const GICD_IGROUPRNE: usize = 0x1000;
+pub const GICD_ISENABLERNE: usize = 0x1200;
+pub const GICD_ICENABLERNE: usize = 0x1400;
+pub const GICD_ISPENDRNE: usize = 0x1600;
+pub const GICD_ICPENDRNE: usize = 0x1800;
+pub const GICD_ISACTIVERNE: usize = 0x1a00;
+pub const GICD_ICACTIVERNE: usize = 0x1c00;
+pub const GICD_IPRIORITYRNE: usize = 0x2000;
+pub const GICD_ICFGRNE: usize = 0x3000;
+pub const GICD_IGRPMODRNE: usize = 0x3400;
+pub const GICD_IROUTERNE: usize = 0x8000;
+
 pub const GICDV3_CIDR0: usize = 0xfff0;
 pub const GICDV3_PIDR0: usize = 0xffe0;
 pub const GICDV3_PIDR2: usize = 0xffe8;
@@ -68,6 +92,17 @@ register_range!(GICD_ICFGR, GICD_ICFGR_RANGE, 64, 4);
 register_range!(GICD_IGRPMODR, GICD_IGRPMODR_RANGE, 32, 4);
 // register_range!(GICD_IPRIORITYR, GICD_IPRIORITYR_RANGE, 255, 4);
 register_range!(GICD_IPRIORITYR, GICD_IPRIORITYR_RANGE, 256, 4);
+register_range!(GICD_IROUTERNE, GICD_IROUTERNE_RANGE, 1024, 8);
+register_range!(GICD_ICENABLERNE, GICD_ICENABLERNE_RANGE, 32, 4);
+register_range!(GICD_ISENABLERNE, GICD_ISENABLERNE_RANGE, 32, 4);
+register_range!(GICD_ICPENDRNE, GICD_ICPENDRNE_RANGE, 32, 4);
+register_range!(GICD_ISPENDRNE, GICD_ISPENDRNE_RANGE, 32, 4);
+register_range!(GICD_ICACTIVERNE, GICD_ICACTIVERNE_RANGE, 32, 4);
+register_range!(GICD_ISACTIVERNE, GICD_ISACTIVERNE_RANGE, 32, 4);
+register_range!(GICD_IGROUPRNE, GICD_IGROUPRNE_RANGE, 32, 4);
+register_range!(GICD_ICFGRNE, GICD_ICFGRNE_RANGE, 64, 4);
+register_range!(GICD_IGRPMODRNE, GICD_IGRPMODRNE_RANGE, 32, 4);
+register_range!(GICD_IPRIORITYRNE, GICD_IPRIORITYRNE_RANGE, 256, 4);
 register_range!(GICDV3_CIDR0, GICDV3_CIDR0_RANGE, 4, 4);
 register_range!(GICDV3_PIDR0, GICDV3_PIDR0_RANGE, 4, 4);
 register_range!(GICDV3_PIDR4, GICDV3_PIDR4_RANGE, 4, 4);
@@ -81,19 +116,65 @@ pub const GITS_MPIDR: usize = 0x0018; // read-only, its affinity
 pub const GITS_STATUSR: usize = 0x0040; // error reporting
 pub const GITS_UMSIR: usize = 0x0048; // unmapped msi
 pub const GITS_CBASER: usize = 0x0080; // the addr of command queue
+pub const GITS_CBASER_SIZE_MASK: usize = 0xff; // number of 4 KiB pages minus one
+pub const GITS_CBASER_PA_MASK: usize = 0x000f_ffff_ffff_f000;
+pub const GITS_CQ_OFFSET_MASK: usize = 0xf_ffe0; // CWRITER/CREADR offset, bits [19:5]
 pub const GITS_CWRITER: usize = 0x0088; // rw, write an command to the cmdq, write this reg to tell hw
+pub const GITS_CWRITER_RETRY: usize = 1;
 pub const GITS_CREADR: usize = 0x0090; // read-only, hardware changes it
+pub const GITS_CREADR_STALLED: usize = 1;
 pub const GITS_BASER: usize = 0x0100; // itt, desc
+pub const GITS_BASER_VALID: usize = 1 << 63;
+pub const GITS_BASER_INDIRECT: usize = 1 << 62;
+pub const GITS_BASER_PA_MASK: usize = 0x0000_ffff_ffff_f000;
+pub const GITS_BASER_PAGE_SIZE_SHIFT: usize = 8;
+pub const GITS_BASER_SIZE_MASK: usize = 0xff; // number of pages minus one
 pub const GITS_DT_BASER: usize = GITS_BASER; // its table 0 used as device table
 pub const GITS_CT_BASER: usize = GITS_BASER + 0x8; // its table 1 used as command table
 pub const GITS_COLLECTION_BASER: usize = GITS_BASER + 0x8;
 pub const GITS_TRANSLATER: usize = 0x10000 + 0x0040; // to signal an interrupt, written by devices
+pub const GITS_PIDR2: usize = 0xffe8;
+pub const ITS_CMD_MOVI: u64 = 0x01;
+pub const ITS_CMD_INT: u64 = 0x03;
+pub const ITS_CMD_CLEAR: u64 = 0x04;
+pub const ITS_CMD_SYNC: u64 = 0x05;
+pub const ITS_CMD_MAPD: u64 = 0x08;
+pub const ITS_CMD_MAPC: u64 = 0x09;
+pub const ITS_CMD_MAPTI: u64 = 0x0a;
+pub const ITS_CMD_MAPI: u64 = 0x0b;
+pub const ITS_CMD_INV: u64 = 0x0c;
+pub const ITS_CMD_INVALL: u64 = 0x0d;
+pub const ITS_CMD_MOVALL: u64 = 0x0e;
+pub const ITS_CMD_DISCARD: u64 = 0x0f;
+pub const ITS_CMD_VMOVI: u64 = 0x21;
+pub const ITS_CMD_VMOVP: u64 = 0x22;
+pub const ITS_CMD_VSYNC: u64 = 0x25;
+pub const ITS_CMD_VMAPP: u64 = 0x29;
+pub const ITS_CMD_VMAPTI: u64 = 0x2a;
+
+pub const GITS_CTLR_ENABLED: usize = 1;
+pub const GITS_CTLR_QUIESCENT: usize = 1 << 31;
+pub const GITS_CTLR_ITS_NUMBER_SHIFT: usize = 4;
+pub const GITS_TYPER_PHYSICAL: usize = 1;
+pub const GITS_TYPER_ITT_ENTRY_SIZE_SHIFT: usize = 4;
+pub const GITS_TYPER_IDBITS_SHIFT: usize = 8;
+pub const GITS_TYPER_DEVBITS_SHIFT: usize = 13;
+pub const GITS_TYPER_HCC_SHIFT: usize = 24;
+pub const GITS_TYPER_VIRTUAL: usize = 1 << 1;
+pub const GITS_TYPER_PTA: usize = 1 << 19;
+pub const GITS_TYPER_VMOVP: usize = 1 << 37;
+pub const GITS_TYPER_VMAPP: usize = 1 << 40;
 
 pub const GICR_CTLR: usize = 0x0000;
+pub const GICR_CTLR_ENABLE_LPIS: usize = 1 << 0;
+pub const GICR_CTLR_RWP: usize = 1 << 3;
+pub const GICR_CTLR_UWP: usize = 1 << 31;
 pub const GICR_IIDR: usize = 0x0004;
 pub const GICR_TYPER: usize = 0x0008;
 pub const GICR_STATUSR: usize = 0x0010;
 pub const GICR_WAKER: usize = 0x0014;
+pub const GICR_WAKER_PROCESSOR_SLEEP: usize = 1 << 1;
+pub const GICR_WAKER_CHILDREN_ASLEEP: usize = 1 << 2;
 pub const GICR_SETLPIR: usize = 0x0040;
 pub const GICR_CLRLPIR: usize = 0x0048;
 pub const GICR_INVLPIR: usize = 0x00a0;
@@ -114,12 +195,34 @@ pub const GICR_ICACTIVER: usize = GICR_SGI_BASE + GICD_ICACTIVER;
 pub const GICR_IPRIORITYR: usize = GICR_SGI_BASE + GICD_IPRIORITYR;
 pub const GICR_ICFGR: usize = GICR_SGI_BASE + GICD_ICFGR;
 pub const GICR_IGRPMODR: usize = GICR_SGI_BASE + GICD_IGRPMODR;
+pub const GICR_TYPER_PLPIS: usize = 1 << 0;
+pub const GICR_TYPER_VLPIS: usize = 1 << 1;
+pub const GICR_TYPER_DIRECT_LPI: usize = 1 << 3;
 pub const GICR_TYPER_LAST: usize = 1 << 4;
+pub const GICR_TYPER_RVPEID: usize = 1 << 7;
+pub const GICR_TYPER_PROCESSOR_NUMBER_SHIFT: usize = 8;
+pub const GICR_TYPER_AFFINITY_SHIFT: usize = 32;
 
 register_range!(GICR_IPRIORITYR, GICR_IPRIORITYR_RANGE, 8, 4);
 register_range!(GICR_ICFGR, GICR_ICFGR_RANGE, 2, 4);
 
 pub const GICR_PROPBASER: usize = 0x0070;
 pub const GICR_PENDBASER: usize = 0x0078;
+pub const GICR_PROPBASER_IDBITS_MASK: usize = 0x1f;
+pub const GICR_PENDBASER_PTZ: usize = 1 << 62;
+
+pub const GICR_VLPI_BASE: usize = 0x20000;
+pub const GICR_VPROPBASER: usize = GICR_VLPI_BASE + 0x0070;
+pub const GICR_VPENDBASER: usize = GICR_VLPI_BASE + 0x0078;
+pub const GICR_VPENDBASER_VALID: usize = 1 << 63;
+pub const GICR_VPENDBASER_DIRTY: usize = 1 << 60;
+pub const GICR_BASER_PA_MASK: usize = 0x000f_ffff_ffff_f000;
+pub const GICR_BASER_SHAREABILITY_MASK: usize = 0b11 << 10;
+pub const GICR_SYNCR_BUSY: usize = 1 << 0;
+
+pub const LPI_BASE: u32 = 8192;
+pub const LPI_PROP_ENABLE: u8 = 1 << 0;
+pub const LPI_PROP_RES1: u8 = 1 << 1;
+pub const LPI_PROP_PRIORITY_MASK: u8 = 0xfc;
 
 pub const MAINTENACE_INTERRUPT: u64 = 25;
@@ -12,8 +12,14 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use axaddrspace::{device::AccessWidth, HostPhysAddr};
-use axerrno::AxResult;
+use core::mem::size_of;
+
+use axaddrspace::{device::AccessWidth, GuestPhysAddr, HostPhysAddr};
+use axerrno::{ax_err, AxResult};
+use axvisor_api::vmm::VMId;
+use memory_addr::{VirtAddr, PAGE_SIZE_4K};
+
+use super::api;
 
 pub(crate) fn perform_mmio_read(addr: HostPhysAddr, width: AccessWidth) -> AxResult<usize> {
     let addr = axvisor_api::memory::phys_to_virt(addr).as_ptr();
@@ -51,4 +57,98 @@ pub(crate) fn perform_mmio_write(
     Ok(())
 }
 
-pub use super::vgicr::enable_one_lpi;
+/// Returns the mask of the bits covered by an access of `width`.
+pub(crate) fn width_mask(width: AccessWidth) -> usize {
+    1usize
+        .unbounded_shl(width.size() as u32 * 8)
+        .wrapping_sub(1)
+}
+
+/// Makes CPU writes to `[addr, addr + size)` visible to the GIC before it is told to re-read them.
+///
+/// Tables of a GIC that is not coherent with the CPU caches are cleaned to the point of coherency.
+pub(crate) fn sync_table_write(addr: VirtAddr, size: usize, coherent: bool) {
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        if !coherent {
+            let ctr: usize;
+            core::arch::asm!("mrs {}, ctr_el0", out(reg) ctr);
+            // CTR_EL0.DminLine is log2 of the number of words in the smallest data cache line.
+            let line = 4 << ((ctr >> 16) & 0xf);
+            let start = addr.as_usize() & !(line - 1);
+            for line_addr in (start..addr.as_usize() + size).step_by(line) {
+                core::arch::asm!("dc cvac, {}", in(reg) line_addr);
+            }
+            // The GIC is outside the inner shareable domain, wait for the whole system.
+            core::arch::asm!("dsb st");
+        } else {
+            core::arch::asm!("dsb ishst");
+        }
+    }
+    #[cfg(not(target_arch = "aarch64"))]
+    let _ = (addr, size, coherent);
+}
+
+/// Reads a `T` from the memory of a VM.
+///
+/// `gpa` is translated through the VM's stage-2 page table, and the read must not cross a page.
+pub(crate) fn read_guest<T: Copy>(vm_id: VMId, gpa: GuestPhysAddr) -> AxResult<T> {
+    let hpa = translate_guest(vm_id, gpa, size_of::<T>())?;
+    let ptr = axvisor_api::memory::phys_to_virt(hpa).as_ptr_of::<T>();
+    Ok(unsafe { ptr.read_volatile() })
+}
+
+/// Writes a `T` to the memory of a VM.
+///
+/// `gpa` is translated through the VM's stage-2 page table, and the write must not cross a page.
+pub(crate) fn write_guest<T: Copy>(vm_id: VMId, gpa: GuestPhysAddr, val: T) -> AxResult {
+    let hpa = translate_guest(vm_id, gpa, size_of::<T>())?;
+    let ptr = axvisor_api::memory::phys_to_virt(hpa).as_mut_ptr_of::<T>();
+    unsafe { ptr.write_volatile(val) };
+    Ok(())
+}
+
+/// Translates `size` bytes of the memory of a VM from `gpa`, for structures the hardware accesses
+/// by host physical address.
+///
+/// Fails with `BadAddress` if any page is not backed by VM memory, or if the range is not
+/// contiguous in host physical memory.
+pub(crate) fn translate_guest_range(
+    vm_id: VMId,
+    gpa: GuestPhysAddr,
+    size: usize,
+) -> AxResult<HostPhysAddr> {
+    let Some(hpa) = api::translate_guest_phys(vm_id, gpa) else {
+        return ax_err!(
+            BadAddress,
+            "Guest physical address is not backed by VM memory"
+        );
+    };
+
+    let first_page = gpa.as_usize() - gpa.as_usize() % PAGE_SIZE_4K;
+    let mut page = first_page + PAGE_SIZE_4K;
+    while page < gpa.as_usize() + size {
+        let expected = hpa + (page - gpa.as_usize());
+        if api::translate_guest_phys(vm_id, GuestPhysAddr::from_usize(page)) != Some(expected) {
+            return ax_err!(
+                BadAddress,
+                "Guest memory range is not contiguous in host memory"
+            );
+        }
+        page += PAGE_SIZE_4K;
+    }
+    Ok(hpa)
+}
+
+fn translate_guest(vm_id: VMId, gpa: GuestPhysAddr, size: usize) -> AxResult<HostPhysAddr> {
+    if gpa.as_usize() % PAGE_SIZE_4K + size > PAGE_SIZE_4K {
+        return ax_err!(InvalidInput, "Guest memory access crosses a page");
+    }
+    match api::translate_guest_phys(vm_id, gpa) {
+        Some(hpa) => Ok(hpa),
+        None => ax_err!(
+            BadAddress,
+            "Guest physical address is not backed by VM memory"
+        ),
+    }
+}
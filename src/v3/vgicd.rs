@@ -12,18 +12,25 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use core::cell::UnsafeCell;
+extern crate alloc;
+
+use alloc::{collections::BTreeMap, vec::Vec};
 
 use axaddrspace::{device::AccessWidth, GuestPhysAddr, GuestPhysAddrRange, HostPhysAddr};
 use axdevice_base::{BaseDeviceOps, EmuDeviceType};
-use axerrno::AxResult;
-use axvisor_api::memory::phys_to_virt;
-use bitmaps::Bitmap;
-use log::debug;
+use axerrno::{ax_err, AxResult};
+use axvisor_api::{
+    memory::phys_to_virt,
+    vmm::{VCpuId, VMId},
+};
+use log::{debug, warn};
+use spin::{Mutex, RwLock};
 
 use super::{
+    api,
     registers::*,
-    utils::{perform_mmio_read, perform_mmio_write},
+    utils::{perform_mmio_read, perform_mmio_write, width_mask},
+    vspi::{is_espi, IrqBitmap, VirtualSpi, VirtualSpiReg, VirtualSpis},
 };
 
 /// Default size for GICD region.
@@ -38,8 +45,16 @@ pub struct VGicD {
     /// The size of the VGicD in bytes.
     pub size: usize,
 
+    /// The VM this VGicD belongs to.
+    pub vm_id: VMId,
+
     /// IRQs assigned to this VGicD.
-    pub assigned_irqs: UnsafeCell<Bitmap<{ MAX_IRQ_V3 }>>,
+    pub assigned_irqs: RwLock<IrqBitmap>,
+    /// Guest view of the interrupt groups of the assigned IRQs and virtual SPIs.
+    pub groups: RwLock<VirtualIrqGroups>,
+    /// State of the SPIs not assigned to this VGicD, which are emulated and injected through
+    /// list registers.
+    pub virtual_spis: Mutex<VirtualSpis>,
 
     /// The host physical address of the VGicD.
     ///
@@ -49,41 +64,93 @@ pub struct VGicD {
 
 impl VGicD {
     /// Creates a new VGicD instance.
-    pub fn new(addr: GuestPhysAddr, size: Option<usize>) -> Self {
+    pub fn new(addr: GuestPhysAddr, size: Option<usize>, vm_id: VMId) -> Self {
         let size = size.unwrap_or(DEFAULT_GICD_SIZE);
 
         Self {
             addr,
             size,
-            assigned_irqs: UnsafeCell::new(Bitmap::new()),
+            vm_id,
+            assigned_irqs: RwLock::new(IrqBitmap::new()),
+            groups: RwLock::new(VirtualIrqGroups::default()),
+            virtual_spis: Mutex::new(VirtualSpis::new()),
             host_gicd_addr: crate::api_reexp::get_host_gicd_base(),
         }
     }
 
     /// Assigns an IRQ to a specific CPU.
-    pub fn assign_irq(&self, irq: u32, cpu_phys_id: usize, target_cpu_affinity: (u8, u8, u8, u8)) {
+    ///
+    /// Both classic INTIDs and Extended SPIs (if the host GICD implements them) can be assigned.
+    /// Fails with `ResourceBusy` if the IRQ is already owned by another VM. Assigning an IRQ this
+    /// VM already owns only updates its routing.
+    pub fn assign_irq(
+        &self,
+        irq: u32,
+        cpu_phys_id: usize,
+        target_cpu_affinity: (u8, u8, u8, u8),
+    ) -> AxResult {
         debug!(
             "Physically assigning IRQ {irq} to CPU {cpu_phys_id} with affinity {target_cpu_affinity:?}"
         );
 
-        if irq >= MAX_IRQ_V3 as u32 {
-            panic!("IRQ {} is out of range for VGicD", irq);
+        if irq >= MAX_IRQ_V3 as u32 && !self.host_has_espi(irq) {
+            return ax_err!(InvalidInput, "IRQ is out of range for VGicD");
         }
-        unsafe {
-            (*self.assigned_irqs.get()).set(irq as usize, true);
+
+        let mut owners = IRQ_OWNERS.lock();
+        match owners.get(&irq) {
+            Some(owner) if owner.vm_id != self.vm_id => {
+                warn!(
+                    "IRQ {irq} is already assigned to VM {}, rejecting assignment to VM {}",
+                    owner.vm_id, self.vm_id
+                );
+                return ax_err!(ResourceBusy, "IRQ is owned by another VM");
+            }
+            Some(_) => {}
+            None => {
+                let (host_group, host_grpmod) = self.pin_host_group(irq)?;
+                // Remember the host routing so that `unassign_irq` can restore it.
+                let owner = IrqOwner {
+                    vm_id: self.vm_id,
+                    host_group,
+                    host_grpmod,
+                    host_itargetsr: if is_espi(irq as usize) {
+                        0
+                    } else {
+                        perform_mmio_read(
+                            self.host_gicd_addr + GICD_ITARGETSR + irq as usize,
+                            AccessWidth::Byte,
+                        )? as u8
+                    },
+                    host_irouter: perform_mmio_read(
+                        self.host_gicd_addr + irouter_reg(irq),
+                        AccessWidth::Qword,
+                    )? as u64,
+                };
+                owners.insert(irq, owner);
+
+                // The guest starts with the group the IRQ is physically pinned to.
+                let mut groups = self.groups.write();
+                groups.igroupr.set(irq as usize, true);
+                groups.igrpmodr.set(irq as usize, false);
+            }
         }
+        self.assigned_irqs.write().set(irq as usize, true);
 
         // TODO: update host GICD_ITARGETSR and GICD_IROUTER registers
-        let gicd_itargetsr_paddr = self.host_gicd_addr + GICD_ITARGETSR + irq as usize;
-        let gicd_itargetsr_vaddr = phys_to_virt(gicd_itargetsr_paddr);
-        unsafe {
-            core::ptr::write_volatile(
-                gicd_itargetsr_vaddr.as_mut_ptr_of::<u8>(),
-                1u8 << (cpu_phys_id),
-            );
+        // Extended SPIs have no GICD_ITARGETSR.
+        if !is_espi(irq as usize) {
+            let gicd_itargetsr_paddr = self.host_gicd_addr + GICD_ITARGETSR + irq as usize;
+            let gicd_itargetsr_vaddr = phys_to_virt(gicd_itargetsr_paddr);
+            unsafe {
+                core::ptr::write_volatile(
+                    gicd_itargetsr_vaddr.as_mut_ptr_of::<u8>(),
+                    1u8 << (cpu_phys_id),
+                );
+            }
         }
 
-        let gicd_irouter_paddr = self.host_gicd_addr + GICD_IROUTER + (irq as usize) * 8;
+        let gicd_irouter_paddr = self.host_gicd_addr + irouter_reg(irq);
         let gicd_irouter_vaddr = phys_to_virt(gicd_irouter_paddr);
         unsafe {
             core::ptr::write_volatile(
@@ -95,6 +162,155 @@ impl VGicD {
                     | target_cpu_affinity.3 as u64,
             );
         }
+
+        Ok(())
+    }
+
+    /// Gives an assigned IRQ back to the host.
+    ///
+    /// The IRQ is disabled, its pending and active states are cleared, and the host routing saved
+    /// by [`VGicD::assign_irq`] is restored before the ownership is released.
+    pub fn unassign_irq(&self, irq: u32) -> AxResult {
+        debug!("Unassigning IRQ {irq} from VM {}", self.vm_id);
+
+        let mut owners = IRQ_OWNERS.lock();
+        let owner = match owners.get(&irq) {
+            Some(owner) if owner.vm_id == self.vm_id => owner,
+            _ => return ax_err!(NotFound, "IRQ is not assigned to this VM"),
+        };
+
+        let gicd_base = self.host_gicd_addr;
+        for (base, ext_base) in [
+            (GICD_ICENABLER, GICD_ICENABLERNE),
+            (GICD_ICPENDR, GICD_ICPENDRNE),
+            (GICD_ICACTIVER, GICD_ICACTIVERNE),
+        ] {
+            let (reg, bit) = irq_bit_reg(irq, base, ext_base);
+            perform_mmio_write(gicd_base + reg, AccessWidth::Dword, bit)?;
+        }
+
+        if !is_espi(irq as usize) {
+            perform_mmio_write(
+                gicd_base + GICD_ITARGETSR + irq as usize,
+                AccessWidth::Byte,
+                owner.host_itargetsr as usize,
+            )?;
+        }
+        perform_mmio_write(
+            gicd_base + irouter_reg(irq),
+            AccessWidth::Qword,
+            owner.host_irouter as usize,
+        )?;
+        self.restore_host_group(irq, owner.host_group, owner.host_grpmod)?;
+
+        owners.remove(&irq);
+        self.assigned_irqs.write().set(irq as usize, false);
+
+        // The IRQ is a virtual SPI from now on, starting from the reset state.
+        if let Some(spi) = self.virtual_spis.lock().get_mut(irq) {
+            *spi = VirtualSpi::default();
+        }
+
+        Ok(())
+    }
+
+    /// Raises a virtual SPI, e.g. on behalf of an emulated device.
+    ///
+    /// The SPI is made pending, and handed to a list register of its target vCPU if it is
+    /// enabled.
+    pub fn inject_spi(&self, irq: u32) -> AxResult {
+        if !self.is_irq_virtual_spi(irq) {
+            return ax_err!(InvalidInput, "IRQ is not a virtual SPI");
+        }
+
+        let target = self.virtual_spis.lock().get_mut(irq).and_then(|spi| {
+            spi.pending = true;
+            self.take_deliverable_spi(spi)
+        });
+        if let Some(vcpu_id) = target {
+            self.inject_virtual_spi(irq, vcpu_id);
+        }
+
+        Ok(())
+    }
+
+    /// Sets an SPI of this VM pending, as a write of `irq` to GICD_SETSPI_NSR.
+    ///
+    /// This is the path for message-based SPIs, e.g. MSIs of emulated PCIe devices on a system
+    /// without an ITS. Assigned SPIs are made pending on the host GICD, virtual SPIs are
+    /// injected.
+    pub fn set_spi_pending(&self, irq: u32) -> AxResult {
+        if self.is_irq_virtual_spi(irq) {
+            self.inject_spi(irq)
+        } else if self.is_irq_assigned(irq) && (self.is_irq_spi(irq) || self.is_irq_espi(irq)) {
+            let (reg, bit) = irq_bit_reg(irq, GICD_ISPENDR, GICD_ISPENDRNE);
+            perform_mmio_write(self.host_gicd_addr + reg, AccessWidth::Dword, bit)
+        } else {
+            ax_err!(InvalidInput, "IRQ is not an SPI of this VM")
+        }
+    }
+
+    /// Clears the pending state of an SPI of this VM, as a write of `irq` to GICD_CLRSPI_NSR.
+    pub fn clear_spi_pending(&self, irq: u32) -> AxResult {
+        if self.is_irq_virtual_spi(irq) {
+            if let Some(spi) = self.virtual_spis.lock().get_mut(irq) {
+                spi.pending = false;
+            }
+            Ok(())
+        } else if self.is_irq_assigned(irq) && (self.is_irq_spi(irq) || self.is_irq_espi(irq)) {
+            let (reg, bit) = irq_bit_reg(irq, GICD_ICPENDR, GICD_ICPENDRNE);
+            perform_mmio_write(self.host_gicd_addr + reg, AccessWidth::Dword, bit)
+        } else {
+            ax_err!(InvalidInput, "IRQ is not an SPI of this VM")
+        }
+    }
+
+    /// Checks if the host GICD implements an Extended SPI.
+    fn host_has_espi(&self, irq: u32) -> bool {
+        let Ok(typer) = perform_mmio_read(self.host_gicd_addr + GICD_TYPER, AccessWidth::Dword)
+        else {
+            return false;
+        };
+        if typer & GICD_TYPER_ESPI == 0 {
+            return false;
+        }
+
+        let espi_range = (typer & GICD_TYPER_ESPI_RANGE_MASK) >> GICD_TYPER_ESPI_RANGE_SHIFT;
+        (ESPI_BASE..ESPI_BASE + 32 * (espi_range + 1)).contains(&(irq as usize))
+    }
+
+    /// Takes a virtual SPI for delivery if it is enabled and pending, and returns the vCPU it is
+    /// to be injected to. The SPI stays pending while its route names no vCPU of the VM.
+    fn take_deliverable_spi(&self, spi: &mut VirtualSpi) -> Option<VCpuId> {
+        if !spi.should_inject() {
+            return None;
+        }
+        let vcpu_id = spi.target_vcpu(self.vm_id)?;
+        spi.pending = false;
+        Some(vcpu_id)
+    }
+
+    /// Hands a virtual SPI taken by [`Self::take_deliverable_spi`] to a list register of
+    /// `vcpu_id`. Must not be called with the `virtual_spis` lock held.
+    fn inject_virtual_spi(&self, irq: u32, vcpu_id: VCpuId) {
+        debug!(
+            "Injecting virtual SPI {irq} to VM {} vCPU {vcpu_id}",
+            self.vm_id
+        );
+        api::inject_virtual_interrupt(self.vm_id, vcpu_id, irq);
+    }
+
+    /// Writes the route of a virtual SPI, and injects the SPI if it becomes deliverable.
+    fn write_virtual_spi_route(&self, irq: u32, reg: usize, width: AccessWidth, val: usize) {
+        let target = self.virtual_spis.lock().get_mut(irq).and_then(|spi| {
+            let shift = (reg & 0x7) * 8;
+            let mask = (width_mask(width) as u64) << shift;
+            spi.route = (spi.route & !mask) | (((val as u64) << shift) & mask);
+            self.take_deliverable_spi(spi)
+        });
+        if let Some(vcpu_id) = target {
+            self.inject_virtual_spi(irq, vcpu_id);
+        }
     }
 }
 
@@ -123,11 +339,26 @@ impl BaseDeviceOps<GuestPhysAddrRange> for VGicD {
 
                 if self.is_irq_assigned(irq) && self.is_irq_spi(irq) {
                     perform_mmio_read(gicd_base + reg, width)
+                } else if let Some(spi) = self.virtual_spis.lock().get(irq) {
+                    let shift = (reg & 0x7) * 8;
+                    Ok(((spi.route >> shift) as usize) & width_mask(width))
                 } else {
                     // If the IRQ is not assigned, return 0
                     Ok(0)
                 }
             }
+            reg if GICD_IROUTERNE_RANGE.contains(&reg) => {
+                let irq = (ESPI_BASE + (reg - GICD_IROUTERNE) / 8) as u32;
+
+                if self.is_irq_assigned(irq) {
+                    perform_mmio_read(gicd_base + reg, width)
+                } else if let Some(spi) = self.virtual_spis.lock().get(irq) {
+                    let shift = (reg & 0x7) * 8;
+                    Ok(((spi.route >> shift) as usize) & width_mask(width))
+                } else {
+                    Ok(0)
+                }
+            }
             reg if GICD_ITARGETSR_RANGE.contains(&reg) => {
                 let irq = (reg - GICD_ITARGETSR) as u32;
 
@@ -145,25 +376,69 @@ impl BaseDeviceOps<GuestPhysAddrRange> for VGicD {
                 || GICD_ICACTIVER_RANGE.contains(&reg)
                 || GICD_ISACTIVER_RANGE.contains(&reg) =>
             {
-                self.irq_masked_read(reg, reg & 0x7f, 0, width, true)
+                Ok(self.irq_masked_read(reg, reg & 0x7f, 0, width, true)?
+                    | self.virtual_spi_read(reg, reg & 0x7f, 0, width))
             }
             reg if GICD_IGROUPR_RANGE.contains(&reg) => {
-                self.irq_masked_read(reg, reg & 0x7f, 0, width, false)
+                Ok(self.group_shadow_read(reg & 0x7f, width, false))
             }
             reg if GICD_IGRPMODR_RANGE.contains(&reg) => {
-                self.irq_masked_read(reg, reg & 0x7f, 0, width, false)
+                Ok(self.group_shadow_read(reg & 0x7f, width, true))
             }
             reg if GICD_ICFGR_RANGE.contains(&reg) => {
-                self.irq_masked_read(reg, reg & 0xff, 1, width, false)
+                Ok(self.irq_masked_read(reg, reg & 0xff, 1, width, false)?
+                    | self.virtual_spi_read(reg, reg & 0xff, 1, width))
             }
             reg if GICD_IPRIORITYR_RANGE.contains(&reg) => {
-                self.irq_masked_read(reg, reg & 0x3ff, 3, width, false)
+                Ok(self.irq_masked_read(reg, reg & 0x3ff, 3, width, false)?
+                    | self.virtual_spi_read(reg, reg & 0x3ff, 3, width))
+            }
+            // Extended SPI registers are handled as if they continued the classic registers at
+            // INTID `ESPI_BASE`.
+            reg if GICD_ICENABLERNE_RANGE.contains(&reg)
+                || GICD_ISENABLERNE_RANGE.contains(&reg)
+                || GICD_ICPENDRNE_RANGE.contains(&reg)
+                || GICD_ISPENDRNE_RANGE.contains(&reg)
+                || GICD_ICACTIVERNE_RANGE.contains(&reg)
+                || GICD_ISACTIVERNE_RANGE.contains(&reg) =>
+            {
+                let reg_offset = (reg & 0x7f) + (ESPI_BASE >> 3);
+                Ok(self.irq_masked_read(reg, reg_offset, 0, width, true)?
+                    | self.virtual_spi_read(reg, reg_offset, 0, width))
+            }
+            reg if GICD_IGROUPRNE_RANGE.contains(&reg) => {
+                Ok(self.group_shadow_read((reg & 0x7f) + (ESPI_BASE >> 3), width, false))
+            }
+            reg if GICD_IGRPMODRNE_RANGE.contains(&reg) => {
+                Ok(self.group_shadow_read((reg & 0x7f) + (ESPI_BASE >> 3), width, true))
+            }
+            reg if GICD_ICFGRNE_RANGE.contains(&reg) => {
+                let reg_offset = (reg & 0xff) + (ESPI_BASE >> 2);
+                Ok(self.irq_masked_read(reg, reg_offset, 1, width, false)?
+                    | self.virtual_spi_read(reg, reg_offset, 1, width))
+            }
+            reg if GICD_IPRIORITYRNE_RANGE.contains(&reg) => {
+                let reg_offset = (reg & 0x3ff) + ESPI_BASE;
+                Ok(self.irq_masked_read(reg, reg_offset, 3, width, false)?
+                    | self.virtual_spi_read(reg, reg_offset, 3, width))
+            }
+            GICD_TYPER => {
+                // Virtual SPIs can use the whole Extended SPI range, and message-based SPIs are
+                // always emulated.
+                let typer = perform_mmio_read(gicd_base + reg, width)?
+                    | GICD_TYPER_ESPI
+                    | GICD_TYPER_ESPI_RANGE_MASK
+                    | GICD_TYPER_MBIS;
+                Ok(typer & width_mask(width))
+            }
+            GICD_SETSPI_NSR | GICD_CLRSPI_NSR => {
+                // write-only
+                Ok(0)
             }
             reg if GICDV3_PIDR0_RANGE.contains(&reg)
                 || GICDV3_PIDR4_RANGE.contains(&reg)
                 || GICDV3_CIDR0_RANGE.contains(&reg)
                 || reg == GICD_CTLR
-                || reg == GICD_TYPER
                 || reg == GICD_IIDR
                 || reg == GICD_TYPER2 =>
             {
@@ -195,7 +470,17 @@ impl BaseDeviceOps<GuestPhysAddrRange> for VGicD {
                 if self.is_irq_assigned(irq) && self.is_irq_spi(irq) {
                     perform_mmio_write(gicd_base + reg, width, val)
                 } else {
-                    // If the IRQ is not assigned, ignore the write
+                    self.write_virtual_spi_route(irq, reg, width, val);
+                    Ok(())
+                }
+            }
+            reg if GICD_IROUTERNE_RANGE.contains(&reg) => {
+                let irq = (ESPI_BASE + (reg - GICD_IROUTERNE) / 8) as u32;
+
+                if self.is_irq_assigned(irq) {
+                    perform_mmio_write(gicd_base + reg, width, val)
+                } else {
+                    self.write_virtual_spi_route(irq, reg, width, val);
                     Ok(())
                 }
             }
@@ -216,19 +501,59 @@ impl BaseDeviceOps<GuestPhysAddrRange> for VGicD {
                 || GICD_ICACTIVER_RANGE.contains(&reg)
                 || GICD_ISACTIVER_RANGE.contains(&reg) =>
             {
-                self.irq_masked_write(reg, reg & 0x7f, 0, width, true, val)
+                self.irq_masked_write(reg, reg & 0x7f, 0, width, true, val)?;
+                self.virtual_spi_write(reg, reg & 0x7f, 0, width, val);
+                Ok(())
             }
             reg if GICD_IGROUPR_RANGE.contains(&reg) => {
-                self.irq_masked_write(reg, reg & 0x7f, 0, width, false, val)
+                self.group_shadow_write(reg & 0x7f, width, false, val);
+                Ok(())
             }
             reg if GICD_IGRPMODR_RANGE.contains(&reg) => {
-                self.irq_masked_write(reg, reg & 0x7f, 0, width, false, val)
+                self.group_shadow_write(reg & 0x7f, width, true, val);
+                Ok(())
             }
             reg if GICD_ICFGR_RANGE.contains(&reg) => {
-                self.irq_masked_write(reg, reg & 0xff, 1, width, false, val)
+                self.irq_masked_write(reg, reg & 0xff, 1, width, false, val)?;
+                self.virtual_spi_write(reg, reg & 0xff, 1, width, val);
+                Ok(())
             }
             reg if GICD_IPRIORITYR_RANGE.contains(&reg) => {
-                self.irq_masked_write(reg, reg & 0x3ff, 3, width, false, val)
+                self.irq_masked_write(reg, reg & 0x3ff, 3, width, false, val)?;
+                self.virtual_spi_write(reg, reg & 0x3ff, 3, width, val);
+                Ok(())
+            }
+            reg if GICD_ICENABLERNE_RANGE.contains(&reg)
+                || GICD_ISENABLERNE_RANGE.contains(&reg)
+                || GICD_ICPENDRNE_RANGE.contains(&reg)
+                || GICD_ISPENDRNE_RANGE.contains(&reg)
+                || GICD_ICACTIVERNE_RANGE.contains(&reg)
+                || GICD_ISACTIVERNE_RANGE.contains(&reg) =>
+            {
+                let reg_offset = (reg & 0x7f) + (ESPI_BASE >> 3);
+                self.irq_masked_write(reg, reg_offset, 0, width, true, val)?;
+                self.virtual_spi_write(reg, reg_offset, 0, width, val);
+                Ok(())
+            }
+            reg if GICD_IGROUPRNE_RANGE.contains(&reg) => {
+                self.group_shadow_write((reg & 0x7f) + (ESPI_BASE >> 3), width, false, val);
+                Ok(())
+            }
+            reg if GICD_IGRPMODRNE_RANGE.contains(&reg) => {
+                self.group_shadow_write((reg & 0x7f) + (ESPI_BASE >> 3), width, true, val);
+                Ok(())
+            }
+            reg if GICD_ICFGRNE_RANGE.contains(&reg) => {
+                let reg_offset = (reg & 0xff) + (ESPI_BASE >> 2);
+                self.irq_masked_write(reg, reg_offset, 1, width, false, val)?;
+                self.virtual_spi_write(reg, reg_offset, 1, width, val);
+                Ok(())
+            }
+            reg if GICD_IPRIORITYRNE_RANGE.contains(&reg) => {
+                let reg_offset = (reg & 0x3ff) + ESPI_BASE;
+                self.irq_masked_write(reg, reg_offset, 3, width, false, val)?;
+                self.virtual_spi_write(reg, reg_offset, 3, width, val);
+                Ok(())
             }
             reg if GICDV3_PIDR0_RANGE.contains(&reg)
                 || GICDV3_PIDR4_RANGE.contains(&reg)
@@ -242,6 +567,20 @@ impl BaseDeviceOps<GuestPhysAddrRange> for VGicD {
                 // ignore write
                 Ok(())
             }
+            GICD_SETSPI_NSR | GICD_CLRSPI_NSR => {
+                let irq = (val & GICD_SPI_INTID_MASK) as u32;
+                let result = if reg == GICD_SETSPI_NSR {
+                    self.set_spi_pending(irq)
+                } else {
+                    self.clear_spi_pending(irq)
+                };
+
+                // Writes of INTIDs that are not SPIs of this VM are ignored.
+                if result.is_err() {
+                    warn!("vGICD: ignoring message-based SPI write for IRQ {irq}");
+                }
+                Ok(())
+            }
             _ => {
                 todo!("vgicdv3 write unimplemented for reg {:#x}", reg);
             }
@@ -252,7 +591,7 @@ impl BaseDeviceOps<GuestPhysAddrRange> for VGicD {
 impl VGicD {
     /// Checks if an IRQ is assigned to this VGicD.
     pub fn is_irq_assigned(&self, irq: u32) -> bool {
-        unsafe { (*self.assigned_irqs.get()).get(irq as usize) }
+        self.assigned_irqs.read().get(irq as usize)
     }
 
     /// Checks if an IRQ is a Software Generated Interrupt (SGI).
@@ -264,7 +603,17 @@ impl VGicD {
     /// Checks if an IRQ is a Shared Peripheral Interrupt (SPI).
     pub fn is_irq_spi(&self, irq: u32) -> bool {
         // Check if the IRQ is a Shared Peripheral Interrupt (SPI)
-        (16..1020).contains(&irq)
+        (32..1020).contains(&irq)
+    }
+
+    /// Checks if an IRQ is an Extended SPI.
+    pub fn is_irq_espi(&self, irq: u32) -> bool {
+        is_espi(irq as usize)
+    }
+
+    /// Checks if an IRQ is a virtual SPI, i.e. an SPI or Extended SPI not assigned to this VGicD.
+    pub fn is_irq_virtual_spi(&self, irq: u32) -> bool {
+        (self.is_irq_spi(irq) || self.is_irq_espi(irq)) && !self.is_irq_assigned(irq)
     }
 
     /// Returns the mask of bits for the irqs assigned to this VGicD, in a bit-field reg.
@@ -273,12 +622,36 @@ impl VGicD {
         reg_offset: usize,
         bits_per_irq_shift: usize,
         width: AccessWidth,
+    ) -> usize {
+        let assigned = self.assigned_irqs.read();
+        self.irq_mask_by(reg_offset, bits_per_irq_shift, width, |irq| {
+            assigned.get(irq)
+        })
+    }
+
+    /// Returns the mask of bits for the virtual SPIs of this VGicD, in a bit-field reg.
+    pub fn virtual_spi_access_mask(
+        &self,
+        reg_offset: usize,
+        bits_per_irq_shift: usize,
+        width: AccessWidth,
+    ) -> usize {
+        let assigned = self.assigned_irqs.read();
+        self.irq_mask_by(reg_offset, bits_per_irq_shift, width, |irq| {
+            (self.is_irq_spi(irq as _) || is_espi(irq)) && !assigned.get(irq)
+        })
+    }
+
+    /// Returns the mask of bits for the irqs matching `pred`, in a bit-field reg.
+    fn irq_mask_by(
+        &self,
+        reg_offset: usize,
+        bits_per_irq_shift: usize,
+        width: AccessWidth,
+        pred: impl Fn(usize) -> bool,
     ) -> usize {
         if bits_per_irq_shift > 3 {
-            panic!(
-                "bits_per_irq_shift must be <= 3, got {}",
-                bits_per_irq_shift
-            );
+            panic!("bits_per_irq_shift must be <= 3, got {bits_per_irq_shift}");
         }
 
         // How many IRQs there are in the mmio region the access width covers?
@@ -286,11 +659,11 @@ impl VGicD {
         // The first IRQ at the given register offset.
         let first_irq = reg_offset << (3 - bits_per_irq_shift);
         // The mask of a single IRQ in the bit-field register.
-        let single_irq_mask = (1 << (bits_per_irq_shift + 1)) - 1;
+        let single_irq_mask = (1 << (1 << bits_per_irq_shift)) - 1;
 
         let mut mask = 0;
         for irq in 0..irqs_in_access_width {
-            if self.is_irq_assigned((first_irq + irq) as _) {
+            if pred(first_irq + irq) {
                 // If the IRQ is assigned, set the corresponding bits in the mask.
                 mask |= single_irq_mask << (irq << bits_per_irq_shift);
             }
@@ -299,6 +672,78 @@ impl VGicD {
         mask
     }
 
+    /// Reads the bits of the virtual SPIs in a bit-field GICD register.
+    fn virtual_spi_read(
+        &self,
+        offset: usize,
+        reg_offset: usize,
+        bits_per_irq_shift: usize,
+        width: AccessWidth,
+    ) -> usize {
+        let Some(spi_reg) = VirtualSpiReg::from_reg(offset) else {
+            return 0;
+        };
+        let mask = self.virtual_spi_access_mask(reg_offset, bits_per_irq_shift, width);
+        if mask == 0 {
+            return 0;
+        }
+
+        let first_irq = reg_offset << (3 - bits_per_irq_shift);
+        let spis = self.virtual_spis.lock();
+
+        let mut value = 0;
+        for irq in 0..width.size() << (3 - bits_per_irq_shift) {
+            if let Some(spi) = spis.get((first_irq + irq) as _) {
+                value |= spi.read(spi_reg) << (irq << bits_per_irq_shift);
+            }
+        }
+
+        value & mask
+    }
+
+    /// Writes the bits of the virtual SPIs in a bit-field GICD register, and injects the SPIs
+    /// that become deliverable.
+    fn virtual_spi_write(
+        &self,
+        offset: usize,
+        reg_offset: usize,
+        bits_per_irq_shift: usize,
+        width: AccessWidth,
+        val: usize,
+    ) {
+        let Some(spi_reg) = VirtualSpiReg::from_reg(offset) else {
+            return;
+        };
+        let mask = self.virtual_spi_access_mask(reg_offset, bits_per_irq_shift, width);
+        if mask == 0 {
+            return;
+        }
+
+        let first_irq = reg_offset << (3 - bits_per_irq_shift);
+        let single_irq_mask = (1 << (1 << bits_per_irq_shift)) - 1;
+        let mut spis = self.virtual_spis.lock();
+        let mut deliverable = Vec::new();
+
+        for irq in 0..width.size() << (3 - bits_per_irq_shift) {
+            let shift = irq << bits_per_irq_shift;
+            if mask & (single_irq_mask << shift) == 0 {
+                continue;
+            }
+            let irq = (first_irq + irq) as u32;
+            if let Some(spi) = spis.get_mut(irq) {
+                spi.write(spi_reg, (val >> shift) & single_irq_mask);
+                if let Some(vcpu_id) = self.take_deliverable_spi(spi) {
+                    deliverable.push((irq, vcpu_id));
+                }
+            }
+        }
+        drop(spis);
+
+        for (irq, vcpu_id) in deliverable {
+            self.inject_virtual_spi(irq, vcpu_id);
+        }
+    }
+
     /// Performs masked read access to GICD registers.
     pub fn irq_masked_read(
         &self,
@@ -337,5 +782,140 @@ impl VGicD {
     }
 }
 
+/// Guest view of GICD_IGROUPR and GICD_IGRPMODR.
+///
+/// Assigned IRQs are physically pinned to Non-secure Group 1, so guest writes to the group
+/// registers only change these shadow bits and never reach the host GICD. Virtual SPIs have no
+/// physical counterpart and use the same shadow bits.
+#[derive(Default)]
+pub struct VirtualIrqGroups {
+    /// Shadow of GICD_IGROUPR and GICD_IGROUPRnE.
+    pub igroupr: IrqBitmap,
+    /// Shadow of GICD_IGRPMODR and GICD_IGRPMODRnE.
+    pub igrpmodr: IrqBitmap,
+}
+
+impl VGicD {
+    /// Pins an IRQ to Non-secure Group 1 on the host GICD.
+    ///
+    /// Returns the previous (group, group modifier) bits of the IRQ.
+    fn pin_host_group(&self, irq: u32) -> AxResult<(bool, bool)> {
+        let (reg, bit) = irq_bit_reg(irq, GICD_IGROUPR, GICD_IGROUPRNE);
+        let igroupr = self.host_gicd_addr + reg;
+        let (reg, _) = irq_bit_reg(irq, GICD_IGRPMODR, GICD_IGRPMODRNE);
+        let igrpmodr = self.host_gicd_addr + reg;
+
+        let _lock = GICD_LOCK.lock();
+
+        let group = perform_mmio_read(igroupr, AccessWidth::Dword)?;
+        let grpmod = perform_mmio_read(igrpmodr, AccessWidth::Dword)?;
+        perform_mmio_write(igroupr, AccessWidth::Dword, group | bit)?;
+        perform_mmio_write(igrpmodr, AccessWidth::Dword, grpmod & !bit)?;
+
+        Ok((group & bit != 0, grpmod & bit != 0))
+    }
+
+    /// Restores the host group bits of an IRQ saved by [`VGicD::pin_host_group`].
+    fn restore_host_group(&self, irq: u32, group: bool, grpmod: bool) -> AxResult {
+        let (reg, bit) = irq_bit_reg(irq, GICD_IGROUPR, GICD_IGROUPRNE);
+        let igroupr = self.host_gicd_addr + reg;
+        let (reg, _) = irq_bit_reg(irq, GICD_IGRPMODR, GICD_IGRPMODRNE);
+        let igrpmodr = self.host_gicd_addr + reg;
+
+        let _lock = GICD_LOCK.lock();
+
+        let value = perform_mmio_read(igroupr, AccessWidth::Dword)? & !bit;
+        perform_mmio_write(
+            igroupr,
+            AccessWidth::Dword,
+            value | if group { bit } else { 0 },
+        )?;
+        let value = perform_mmio_read(igrpmodr, AccessWidth::Dword)? & !bit;
+        perform_mmio_write(
+            igrpmodr,
+            AccessWidth::Dword,
+            value | if grpmod { bit } else { 0 },
+        )
+    }
+
+    /// Reads the shadow GICD_IGROUPR (or GICD_IGRPMODR if `is_grpmod`) bits of assigned IRQs.
+    fn group_shadow_read(&self, reg_offset: usize, width: AccessWidth, is_grpmod: bool) -> usize {
+        let mask = self.irq_access_mask(reg_offset, 0, width)
+            | self.virtual_spi_access_mask(reg_offset, 0, width);
+        let first_irq = reg_offset << 3;
+        let groups = self.groups.read();
+        let shadow = if is_grpmod {
+            &groups.igrpmodr
+        } else {
+            &groups.igroupr
+        };
+
+        (0..width.size() * 8)
+            .filter(|&bit| shadow.get(first_irq + bit))
+            .fold(0, |value, bit| value | (1 << bit))
+            & mask
+    }
+
+    /// Writes the shadow GICD_IGROUPR (or GICD_IGRPMODR if `is_grpmod`) bits of assigned IRQs.
+    fn group_shadow_write(
+        &self,
+        reg_offset: usize,
+        width: AccessWidth,
+        is_grpmod: bool,
+        val: usize,
+    ) {
+        let mask = self.irq_access_mask(reg_offset, 0, width)
+            | self.virtual_spi_access_mask(reg_offset, 0, width);
+        let first_irq = reg_offset << 3;
+        let mut groups = self.groups.write();
+        let shadow = if is_grpmod {
+            &mut groups.igrpmodr
+        } else {
+            &mut groups.igroupr
+        };
+
+        for bit in (0..width.size() * 8).filter(|&bit| mask & (1 << bit) != 0) {
+            shadow.set(first_irq + bit, val & (1 << bit) != 0);
+        }
+    }
+}
+
+/// Returns the offset and the mask of the bit of `irq` in a one-bit-per-IRQ GICD register, whose
+/// classic and extended instances start at `base` and `ext_base`.
+fn irq_bit_reg(irq: u32, base: usize, ext_base: usize) -> (usize, usize) {
+    let (base, n) = if is_espi(irq as usize) {
+        (ext_base, irq as usize - ESPI_BASE)
+    } else {
+        (base, irq as usize)
+    };
+    (base + (n / 32) * 4, 1 << (n % 32))
+}
+
+/// Returns the offset of the GICD_IROUTER (or GICD_IROUTERnE) register of `irq`.
+fn irouter_reg(irq: u32) -> usize {
+    if is_espi(irq as usize) {
+        GICD_IROUTERNE + (irq as usize - ESPI_BASE) * 8
+    } else {
+        GICD_IROUTER + (irq as usize) * 8
+    }
+}
+
 // Todo: move this lock to arceos or axvisor
-static GICD_LOCK: spin::Mutex<()> = spin::Mutex::new(());
+static GICD_LOCK: Mutex<()> = Mutex::new(());
+
+/// Ownership record of a physical IRQ assigned to a VM.
+struct IrqOwner {
+    /// The VM owning the IRQ.
+    vm_id: VMId,
+    /// Host GICD_IGROUPR bit before the IRQ was assigned.
+    host_group: bool,
+    /// Host GICD_IGRPMODR bit before the IRQ was assigned.
+    host_grpmod: bool,
+    /// Host GICD_ITARGETSR value before the IRQ was assigned.
+    host_itargetsr: u8,
+    /// Host GICD_IROUTER value before the IRQ was assigned.
+    host_irouter: u64,
+}
+
+/// Host-wide registry of assigned IRQs, shared by all VGicD instances.
+static IRQ_OWNERS: Mutex<BTreeMap<u32, IrqOwner>> = Mutex::new(BTreeMap::new());
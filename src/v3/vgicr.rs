@@ -12,18 +12,28 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+extern crate alloc;
+
+use alloc::{vec, vec::Vec};
 use core::{cell::UnsafeCell, ptr};
 
-use axaddrspace::{GuestPhysAddr, GuestPhysAddrRange, HostPhysAddr};
+use axaddrspace::{device::AccessWidth, GuestPhysAddr, GuestPhysAddrRange, HostPhysAddr};
 use axdevice_base::BaseDeviceOps;
-use axvisor_api::memory::phys_to_virt;
-use log::{debug, trace};
+use axerrno::{ax_err, AxResult};
+use axvisor_api::{memory::phys_to_virt, vmm::VMId};
+use log::{debug, trace, warn};
 use memory_addr::PhysAddr;
-use spin::{Mutex, Once};
+use spin::RwLock;
 
 use super::{
+    gits::{
+        host_collections, host_lpi_event, host_sync_cmds, retarget_vcpu_collections, send_host_cmds,
+    },
+    host_gicr::{bind_vcpu_gicr, find_host_gicr, host_gicrs, mpidr_to_affinity},
+    lpi,
     registers::*,
-    utils::{perform_mmio_read, perform_mmio_write},
+    utils::{perform_mmio_read, perform_mmio_write, sync_table_write, width_mask},
+    vpe,
 };
 
 /// Default size per GICR region.
@@ -33,6 +43,73 @@ pub const DEFAULT_SIZE_PER_GICR: usize = 0x20000; // 128K: 64K for SGI/PPI, then
 pub struct VGicRRegs {
     /// LPI configuration table base address.
     pub propbaser: usize,
+    /// Shadow GICR_PENDBASER, the host one points at a pending table owned by the hypervisor.
+    pub pendbaser: usize,
+    /// Virtual GICR_CTLR, only EnableLPIs is kept here.
+    pub ctlr: usize,
+    /// Virtual GICR_WAKER.
+    pub waker: usize,
+}
+
+/// Per-vCPU configuration of a [`VGicR`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct VGicRConfig {
+    /// The VM the vCPU belongs to.
+    pub vm_id: VMId,
+    /// Index of the vCPU in its VM, reported as GICR_TYPER.Processor_Number.
+    pub vcpu_id: usize,
+    /// Virtual MPIDR_EL1 of the vCPU, reported as GICR_TYPER.Affinity_Value.
+    pub vcpu_mpidr: u64,
+    /// Whether this is the last redistributor of the VM's redistributor region.
+    pub is_last: bool,
+    /// Whether the VM supports physical LPIs (GICR_TYPER.PLPIS).
+    pub plpis: bool,
+    /// Whether the VM supports virtual LPIs (GICR_TYPER.VLPIS).
+    pub vlpis: bool,
+    /// Whether GICR_SETLPIR and GICR_CLRLPIR are available to the VM (GICR_TYPER.DirectLPI).
+    pub direct_lpi: bool,
+    /// SGIs and PPIs the hypervisor keeps for itself, one bit per INTID, e.g. the hypervisor
+    /// timer and host IPIs. The guest cannot change their configuration. The maintenance
+    /// interrupt is always reserved.
+    pub reserved_private_irqs: u32,
+}
+
+impl VGicRConfig {
+    /// Builds the GICR_TYPER value the guest sees.
+    pub fn typer(&self) -> usize {
+        let affinity = mpidr_to_affinity(self.vcpu_mpidr) as usize;
+
+        let mut value = (affinity << GICR_TYPER_AFFINITY_SHIFT)
+            | ((self.vcpu_id & 0xffff) << GICR_TYPER_PROCESSOR_NUMBER_SHIFT);
+        if self.is_last {
+            value |= GICR_TYPER_LAST;
+        }
+        if self.plpis {
+            value |= GICR_TYPER_PLPIS;
+        }
+        if self.vlpis {
+            value |= GICR_TYPER_VLPIS;
+        }
+        if self.direct_lpi {
+            value |= GICR_TYPER_DIRECT_LPI;
+        }
+
+        value
+    }
+
+    /// The SGIs and PPIs the guest cannot change, one bit per INTID.
+    pub fn reserved_mask(&self) -> u32 {
+        self.reserved_private_irqs | 1 << MAINTENACE_INTERRUPT
+    }
+}
+
+/// The host redistributor a [`VGicR`] is currently bound to.
+#[derive(Clone, Copy, Debug)]
+pub struct HostGicrBinding {
+    /// MPIDR_EL1 of the physical CPU the vCPU runs on.
+    pub mpidr: u64,
+    /// Host physical base address of the GICR of that CPU.
+    pub base: HostPhysAddr,
 }
 
 /// Virtual GICv3 Redistributor.
@@ -42,10 +119,10 @@ pub struct VGicR {
     /// The size of the VGicR in bytes.
     pub size: usize,
 
-    /// CPU ID associated with this redistributor.
-    pub cpu_id: usize,
-    /// Host physical base address of GICR for this CPU.
-    pub host_gicr_base_this_cpu: HostPhysAddr,
+    /// Configuration of the vCPU this redistributor belongs to.
+    pub config: VGicRConfig,
+    /// The host redistributor of the physical CPU the vCPU currently runs on.
+    host: RwLock<HostGicrBinding>,
 
     /// Virtual GICR registers.
     pub regs: UnsafeCell<VGicRRegs>,
@@ -63,21 +140,180 @@ impl VGicR {
         unsafe { &mut *self.regs.get() }
     }
 
-    /// Creates a new VGicR instance.
-    pub fn new(addr: GuestPhysAddr, size: Option<usize>, cpu_id: usize) -> Self {
+    /// Creates a new VGicR instance, bound to the host redistributor of the physical CPU with
+    /// MPIDR_EL1 value `host_mpidr`.
+    ///
+    /// Fails with `NotFound` if no host redistributor belongs to that CPU.
+    pub fn new(
+        addr: GuestPhysAddr,
+        size: Option<usize>,
+        host_mpidr: u64,
+        config: VGicRConfig,
+    ) -> AxResult<Self> {
         let size = size.unwrap_or(DEFAULT_SIZE_PER_GICR);
-        let host_gicr_base_this_cpu = crate::api_reexp::get_host_gicr_base() + cpu_id * size;
-
-        Self {
+        let Some(gicr) = find_host_gicr(host_mpidr) else {
+            return ax_err!(NotFound, "No host redistributor for the given MPIDR");
+        };
+        bind_vcpu_gicr(config.vm_id, config.vcpu_id, addr, config.vcpu_mpidr, gicr);
+        let host = HostGicrBinding {
+            mpidr: host_mpidr,
+            base: gicr.base,
+        };
+
+        Ok(Self {
             addr,
             size,
-            cpu_id,
-            host_gicr_base_this_cpu,
-            regs: UnsafeCell::new(VGicRRegs { propbaser: 0 }),
+            config,
+            host: RwLock::new(host),
+            regs: UnsafeCell::new(VGicRRegs {
+                propbaser: 0,
+                pendbaser: 0,
+                ctlr: 0,
+                // The redistributor of a vCPU that has not been woken up yet is asleep.
+                waker: GICR_WAKER_PROCESSOR_SLEEP | GICR_WAKER_CHILDREN_ASLEEP,
+            }),
+        })
+    }
+
+    /// Gets the host redistributor this VGicR is currently bound to.
+    pub fn host(&self) -> HostGicrBinding {
+        *self.host.read()
+    }
+
+    /// Rebinds this VGicR to the host redistributor of the physical CPU with MPIDR_EL1 value
+    /// `host_mpidr`, to be called when the vCPU is migrated to another physical CPU.
+    ///
+    /// The private SGI/PPI configuration the guest programmed (enable, priority, config and
+    /// group) is moved to the new redistributor, and so are the host ITS collections the guest
+    /// mapped to this vCPU.
+    pub fn migrate(&self, host_mpidr: u64) -> AxResult {
+        let mut host = self.host.write();
+        if host.mpidr == host_mpidr {
+            return Ok(());
+        }
+
+        let Some(gicr) = find_host_gicr(host_mpidr) else {
+            return ax_err!(NotFound, "No host redistributor for the given MPIDR");
+        };
+        debug!(
+            "vGICR {} migrating from MPIDR {:#x} ({:#x}) to MPIDR {host_mpidr:#x} ({:#x})",
+            self.config.vcpu_id, host.mpidr, host.base, gicr.base
+        );
+        // Collections of VMs with vPEs are virtual and follow the vPE. The others are moved
+        // first, nothing has changed yet if that fails.
+        let retarget = !vpe::has_vpes(self.config.vm_id);
+        if retarget {
+            retarget_vcpu_collections(self.config.vm_id, self.config.vcpu_id, gicr)?;
+        }
+        if let Err(err) = move_private_config(host.base, gicr.base, self.config.reserved_mask()) {
+            if let Some(old) = find_host_gicr(host.mpidr).filter(|_| retarget) {
+                if let Err(err) =
+                    retarget_vcpu_collections(self.config.vm_id, self.config.vcpu_id, old)
+                {
+                    warn!("Failed to move the ITS collections back: {err:?}");
+                }
+            }
+            return Err(err);
+        }
+        bind_vcpu_gicr(
+            self.config.vm_id,
+            self.config.vcpu_id,
+            self.addr,
+            self.config.vcpu_mpidr,
+            gicr,
+        );
+
+        *host = HostGicrBinding {
+            mpidr: host_mpidr,
+            base: gicr.base,
+        };
+        Ok(())
+    }
+
+    /// Makes the vPE of this vCPU resident on the host redistributor, to be called when the
+    /// vCPU is loaded on a physical CPU. Does nothing for VMs without GICv4 vPEs.
+    pub fn vcpu_load(&self) -> AxResult {
+        if !vpe::has_vpes(self.config.vm_id) {
+            return Ok(());
+        }
+        vpe::vpe_load(self.config.vm_id, self.config.vcpu_id, self.host().mpidr)
+    }
+
+    /// Makes the vPE of this vCPU non-resident, to be called when the vCPU is put.
+    pub fn vcpu_put(&self) -> AxResult {
+        if !vpe::has_vpes(self.config.vm_id) {
+            return Ok(());
         }
+        vpe::vpe_put(self.config.vm_id, self.config.vcpu_id)
+    }
+
+    /// Makes a virtual LPI pending on this redistributor, as a GICR_SETLPIR write does.
+    ///
+    /// The LPI is injected as soon as it is enabled in the guest's LPI configuration table and
+    /// the guest has enabled LPIs. Fails with `BadState` if the guest has not programmed
+    /// GICR_PROPBASER, and with `InvalidInput` if `intid` is not in its LPI space.
+    pub fn set_lpi_pending(&self, intid: u32) -> AxResult {
+        lpi::set_virtual_pending(self.config.vm_id, self.config.vcpu_id, intid)
+    }
+
+    /// Clears the pending state of a virtual LPI, as a GICR_CLRLPIR write does.
+    ///
+    /// LPIs already handed to a list register are not affected.
+    pub fn clear_lpi_pending(&self, intid: u32) -> AxResult {
+        if intid < LPI_BASE {
+            return ax_err!(InvalidInput, "INTID is not an LPI");
+        }
+
+        lpi::clear_virtual_pending(self.config.vm_id, self.config.vcpu_id, intid);
+        Ok(())
+    }
+
+    /// Injects the pending virtual LPIs that the guest has enabled.
+    fn flush_pending_lpis(&self) {
+        lpi::flush_virtual_pending(self.config.vm_id, self.config.vcpu_id);
     }
 }
 
+/// Copies the SGI/PPI configuration from one host redistributor to another.
+///
+/// The reserved SGIs and PPIs in `reserved` keep the configuration of the new redistributor.
+fn move_private_config(from: HostPhysAddr, to: HostPhysAddr, reserved: u32) -> AxResult {
+    let regs = [GICR_IGROUPR, GICR_IGRPMODR]
+        .into_iter()
+        .chain(GICR_IPRIORITYR_RANGE.step_by(4))
+        .chain(GICR_ICFGR_RANGE.step_by(4));
+    for reg in regs {
+        let mask = reserved_field_mask(reserved, reg, AccessWidth::Dword);
+        let value = perform_mmio_read(from + reg, AccessWidth::Dword)? & !mask;
+        let kept = perform_mmio_read(to + reg, AccessWidth::Dword)? & mask;
+        perform_mmio_write(to + reg, AccessWidth::Dword, value | kept)?;
+    }
+
+    let enabled =
+        perform_mmio_read(from + GICR_ISENABLER, AccessWidth::Dword)? & !reserved as usize;
+    let disabled = !enabled & !reserved as usize & 0xffff_ffff;
+    perform_mmio_write(to + GICR_ICENABLER, AccessWidth::Dword, disabled)?;
+    perform_mmio_write(to + GICR_ISENABLER, AccessWidth::Dword, enabled)
+}
+
+/// Returns the bits of the SGI_base register `reg` that belong to the SGIs and PPIs in
+/// `reserved`, for an access of `width`.
+fn reserved_field_mask(reserved: u32, reg: usize, width: AccessWidth) -> usize {
+    let (base, bits_per_irq) = match reg {
+        _ if GICR_IPRIORITYR_RANGE.contains(&reg) => (GICR_IPRIORITYR, 8),
+        _ if GICR_ICFGR_RANGE.contains(&reg) => (GICR_ICFGR, 2),
+        GICR_IGROUPR | GICR_IGRPMODR | GICR_ISENABLER | GICR_ICENABLER | GICR_ISPENDR
+        | GICR_ICPENDR | GICR_ISACTIVER | GICR_ICACTIVER => (reg, 1),
+        _ => return 0,
+    };
+    let first_irq = (reg - base) * 8 / bits_per_irq;
+    let field = (1usize << bits_per_irq) - 1;
+
+    (0..width.size() * 8 / bits_per_irq)
+        .filter(|i| first_irq + i < 32 && reserved & (1 << (first_irq + i)) != 0)
+        .fold(0, |mask, i| mask | field << (i * bits_per_irq))
+}
+
 impl BaseDeviceOps<GuestPhysAddrRange> for VGicR {
     fn emu_type(&self) -> axdevice_base::EmuDeviceType {
         axdevice_base::EmuDeviceType::GPPTRedistributor
@@ -92,36 +328,35 @@ impl BaseDeviceOps<GuestPhysAddrRange> for VGicR {
         addr: <GuestPhysAddrRange as axaddrspace::device::DeviceAddrRange>::Addr,
         width: axaddrspace::device::AccessWidth,
     ) -> axerrno::AxResult<usize> {
-        let gicr_base = self.host_gicr_base_this_cpu;
+        let host = self.host();
+        let gicr_base = host.base;
         let reg = addr - self.addr;
 
         debug!(
             "vGICR ({} @ {:#x}) read reg {:#x} width {:?}",
-            self.cpu_id, self.addr, reg, width
+            self.config.vcpu_id, self.addr, reg, width
         );
 
         match reg {
             GICR_CTLR => {
-                // TODO: is cross vcpu access allowed?
-                perform_mmio_read(gicr_base + reg, width)
+                // RWP and UWP track the host redistributor, which the SGI/PPI registers are
+                // passed through to.
+                let host_ctlr = perform_mmio_read(gicr_base + reg, width)?;
+                Ok(self.regs().ctlr | (host_ctlr & (GICR_CTLR_RWP | GICR_CTLR_UWP)))
             }
-            GICR_TYPER => {
-                let mut value = perform_mmio_read(gicr_base + reg, width)?;
-
-                // TODO: set GICR_TYPER_LAST if it is the last redistributor of a VM.
-                if true {
-                    value |= GICR_TYPER_LAST;
-                }
-
-                Ok(value)
+            GICR_WAKER => Ok(self.regs().waker),
+            reg if reg == GICR_TYPER || reg == GICR_TYPER + 4 => {
+                // Synthesized from the vCPU, the host value describes the physical CPU.
+                let shift = (reg - GICR_TYPER) * 8;
+                Ok((self.config.typer() >> shift) & width_mask(width))
             }
             GICR_IIDR | GICR_IMPL_DEF_IDENT_REGS_START..=GICR_IMPL_DEF_IDENT_REGS_END => {
                 // Make these read-only registers accessible.
                 perform_mmio_read(gicr_base + reg, width)
             }
             GICR_PENDBASER => {
-                // every redist have its own pending tbl
-                perform_mmio_read(gicr_base + reg, width)
+                // PTZ is write-only.
+                Ok(self.regs().pendbaser & !GICR_PENDBASER_PTZ)
             }
             GICR_PROPBASER => {
                 // all the redist share one prop tbl
@@ -133,9 +368,10 @@ impl BaseDeviceOps<GuestPhysAddrRange> for VGicR {
                 // always return 0 for synchronization register
                 Ok(0)
             }
-            GICR_SETLPIR | GICR_CLRLPIR | GICR_INVALLR => perform_mmio_read(gicr_base + reg, width),
+            // Write-only.
+            GICR_SETLPIR | GICR_CLRLPIR => Ok(0),
+            GICR_INVALLR => perform_mmio_read(gicr_base + reg, width),
             reg if reg == GICR_STATUSR
-                || reg == GICR_WAKER
                 || reg == GICR_IGROUPR
                 || reg == GICR_ISENABLER
                 || reg == GICR_ICENABLER
@@ -161,39 +397,153 @@ impl BaseDeviceOps<GuestPhysAddrRange> for VGicR {
         width: axaddrspace::device::AccessWidth,
         value: usize,
     ) -> axerrno::AxResult<()> {
-        let gicr_base = self.host_gicr_base_this_cpu;
+        let host = self.host();
+        let gicr_base = host.base;
         let reg = addr - self.addr;
 
         debug!(
             "vGICR ({} @ {:#x}) write reg {:#x} width {:?} value {:#x}",
-            self.cpu_id, self.addr, reg, width, value
+            self.config.vcpu_id, self.addr, reg, width, value
         );
 
         match reg {
             GICR_CTLR => {
-                // TODO: is cross zone access allowed?
-                perform_mmio_write(gicr_base + reg, width, value)
+                let enable_lpis = value & GICR_CTLR_ENABLE_LPIS;
+                let was_enabled = self.regs().ctlr & GICR_CTLR_ENABLE_LPIS != 0;
+                self.regs_mut().ctlr = enable_lpis;
+
+                // Pending bits move between the guest pending table and the physical one when
+                // LPIs are enabled or disabled.
+                let pendbaser = self.regs().pendbaser;
+                if enable_lpis != 0 && !was_enabled {
+                    if let Err(err) = lpi::load_pending(
+                        self.config.vm_id,
+                        self.config.vcpu_id,
+                        pendbaser,
+                        gicr_base,
+                    ) {
+                        warn!("Failed to load the LPI pending table: {err:?}");
+                    }
+                } else if enable_lpis == 0 && was_enabled {
+                    if let Err(err) = lpi::save_pending(
+                        self.config.vm_id,
+                        self.config.vcpu_id,
+                        pendbaser,
+                        gicr_base,
+                    ) {
+                        warn!("Failed to save the LPI pending table: {err:?}");
+                    }
+                }
+
+                // LPIs are enabled on the host redistributor on behalf of VMs allowed to use
+                // them, but never disabled: the host redistributor is shared.
+                if enable_lpis != 0 && self.config.plpis {
+                    let host_ctlr = perform_mmio_read(gicr_base + reg, AccessWidth::Dword)?;
+                    if host_ctlr & GICR_CTLR_ENABLE_LPIS == 0 {
+                        perform_mmio_write(
+                            gicr_base + reg,
+                            AccessWidth::Dword,
+                            host_ctlr | GICR_CTLR_ENABLE_LPIS,
+                        )?;
+                    }
+                }
+
+                // The redistributor reads the configuration table when LPIs get enabled.
+                if enable_lpis != 0 && !was_enabled {
+                    if let Err(err) = lpi::sync_vm_lpis(self.config.vm_id) {
+                        warn!("Failed to apply the LPI configuration table: {err:?}");
+                    }
+                }
+                if (enable_lpis != 0) != was_enabled {
+                    lpi::set_virtual_lpis_enabled(
+                        self.config.vm_id,
+                        self.config.vcpu_id,
+                        enable_lpis != 0,
+                    );
+                }
+                Ok(())
+            }
+            GICR_WAKER => {
+                // Sleeping is purely virtual, the host redistributor stays awake for the
+                // hypervisor. ChildrenAsleep follows ProcessorSleep immediately.
+                self.regs_mut().waker = if value & GICR_WAKER_PROCESSOR_SLEEP != 0 {
+                    GICR_WAKER_PROCESSOR_SLEEP | GICR_WAKER_CHILDREN_ASLEEP
+                } else {
+                    0
+                };
+                Ok(())
             }
             GICR_PENDBASER => {
-                // every redist have its own pending tbl
-                perform_mmio_write(gicr_base + reg, width, value)
+                // Only shadowed, the host pending table belongs to the hypervisor.
+                self.regs_mut().pendbaser = value;
+                Ok(())
             }
             GICR_PROPBASER => {
                 // all the redist share one prop tbl
                 self.regs_mut().propbaser = value;
+                lpi::set_propbaser(self.config.vm_id, value);
                 Ok(())
             }
-            GICR_SETLPIR | GICR_CLRLPIR | GICR_INVALLR => {
-                perform_mmio_write(gicr_base + reg, width, value)
+            GICR_SETLPIR | GICR_CLRLPIR => {
+                if !self.config.direct_lpi {
+                    warn!(
+                        "vGICR {} ignoring {reg:#x} write, DirectLPI is not supported",
+                        self.config.vcpu_id
+                    );
+                    return Ok(());
+                }
+
+                let intid = (value & 0xffff_ffff) as u32;
+                let result = if reg == GICR_SETLPIR {
+                    self.set_lpi_pending(intid)
+                } else {
+                    self.clear_lpi_pending(intid)
+                };
+                if let Err(err) = result {
+                    warn!(
+                        "vGICR {} ignoring {reg:#x} write: {err:?}",
+                        self.config.vcpu_id
+                    );
+                }
+                Ok(())
             }
             GICR_INVLPIR => {
-                // Presume that this write is to enable an LPI.
-                // Or we need to check all the proptbl created by vm.
-                enable_one_lpi((value & 0xffffffff) - 8192); // ⬅️Why?
-                Ok(())
+                // Re-read the guest's property entry, then drop the host's cached copy.
+                let intid = (value & 0xffff_ffff) as u32;
+                let result = match lpi::sync_lpi(self.config.vm_id, intid) {
+                    Ok(Some(physical)) => {
+                        perform_mmio_write(gicr_base + reg, width, physical as usize)
+                    }
+                    Ok(None) => Ok(()),
+                    // Purely virtual LPIs have no physical property.
+                    Err(_)
+                        if lpi::is_virtual_pending(
+                            self.config.vm_id,
+                            self.config.vcpu_id,
+                            intid,
+                        ) =>
+                    {
+                        Ok(())
+                    }
+                    Err(err) => {
+                        warn!(
+                            "vGICR {} rejecting INVLPIR for INTID {intid}: {err:?}",
+                            self.config.vcpu_id
+                        );
+                        Ok(())
+                    }
+                };
+                self.flush_pending_lpis();
+                result
+            }
+            GICR_INVALLR => {
+                if let Err(err) = lpi::sync_vm_lpis(self.config.vm_id) {
+                    warn!("Failed to apply the LPI configuration table: {err:?}");
+                }
+                self.flush_pending_lpis();
+                perform_mmio_write(gicr_base + reg, width, value)
             }
             reg if reg == GICR_STATUSR
-                || reg == GICR_WAKER
                 || reg == GICR_IGROUPR
                 || reg == GICR_ISENABLER
                 || reg == GICR_ICENABLER
@@ -205,12 +555,16 @@ impl BaseDeviceOps<GuestPhysAddrRange> for VGicR {
                 || GICR_IPRIORITYR_RANGE.contains(&reg)
                 || GICR_ICFGR_RANGE.contains(&reg) =>
             {
-                let mut value = value;
-                // avoid linux disable maintenance interrupt
-                if reg == GICR_ICENABLER {
-                    value &= !(1 << MAINTENACE_INTERRUPT);
-                    // value &= !(1 << SGI_IPI_ID);
-                }
+                // Keep the guest away from the reserved SGIs and PPIs, e.g. the maintenance
+                // interrupt. Write-1 registers just drop their bits, the others keep the host
+                // value of the reserved fields.
+                let mask = reserved_field_mask(self.config.reserved_mask(), reg, width);
+                let value = match reg {
+                    _ if mask == 0 => value,
+                    GICR_ISENABLER | GICR_ICENABLER | GICR_ISPENDR | GICR_ICPENDR
+                    | GICR_ISACTIVER | GICR_ICACTIVER => value & !mask,
+                    _ => (perform_mmio_read(gicr_base + reg, width)? & mask) | (value & !mask),
+                };
                 perform_mmio_write(gicr_base + reg, width, value)
             }
             _ => {
@@ -222,95 +576,325 @@ impl BaseDeviceOps<GuestPhysAddrRange> for VGicR {
 
 // todo: move the lpi prop table to arm-gic-driver, and find a good interface to use it.
 /// LPI property table for managing Locality-specific Peripheral Interrupts.
+///
+/// The pending tables of the host redistributors are owned here as well, guests only see shadow
+/// GICR_PENDBASER registers.
 pub struct LpiPropTable {
     frame: PhysAddr,
     frame_pages: usize,
-    _host_gicr_base: HostPhysAddr,
+    /// Whether the host redistributors access the tables coherently with the CPU caches.
+    coherent: bool,
+    pend_frames: Vec<PhysAddr>,
+    pend_frame_pages: usize,
 }
 
 impl Drop for LpiPropTable {
     fn drop(&mut self) {
         trace!("LpiPropTable dropped, frame: {:?}", self.frame);
         axvisor_api::memory::dealloc_contiguous_frames(self.frame, self.frame_pages);
+        for &frame in &self.pend_frames {
+            axvisor_api::memory::dealloc_contiguous_frames(frame, self.pend_frame_pages);
+        }
     }
 }
 
 impl LpiPropTable {
-    fn new(
-        host_gicd_typer: u32,
-        host_gicr_base: HostPhysAddr,
-        size_per_gicr: Option<usize>,
-        cpu_num: usize,
-    ) -> Self {
-        let size_per_gicr = size_per_gicr.unwrap_or(DEFAULT_SIZE_PER_GICR);
+    fn new(host_gicd_typer: u32) -> Self {
         let id_bits = (host_gicd_typer >> 19) & 0x1f;
         let page_num: usize = ((1 << (id_bits + 1)) - 8192) / memory_addr::PAGE_SIZE_4K;
 
-        debug!(
-            "Creating LPI prop table: id_bits: {id_bits}, page_num: {page_num}, size_per_gicr: {size_per_gicr}"
-        );
+        debug!("Creating LPI prop table: id_bits: {id_bits}, page_num: {page_num}");
 
         let f = axvisor_api::memory::alloc_contiguous_frames(page_num, 0)
             .expect("Failed to allocate contiguous frames for LPI prop table");
         let propreg = f.as_usize() | 0x78f;
-        for id in 0..cpu_num {
-            let propbaser = host_gicr_base + id * size_per_gicr + GICR_PROPBASER;
-            let propbaser = phys_to_virt(propbaser);
-            debug!("Setting propbaser for CPU {id}: {propbaser:#x} -> {propreg:#x}");
+        // One pending bit per INTID, 64 KiB aligned.
+        let pend_page_num = (1usize << (id_bits + 1)).div_ceil(8 * memory_addr::PAGE_SIZE_4K);
+        let mut pend_frames = Vec::new();
+        for gicr in host_gicrs() {
+            let propbaser = phys_to_virt(gicr.base + GICR_PROPBASER);
+            debug!(
+                "Setting propbaser for redistributor {:#x}: {propbaser:#x} -> {propreg:#x}",
+                gicr.affinity
+            );
+
+            let pend = axvisor_api::memory::alloc_contiguous_frames(pend_page_num, 4)
+                .expect("Failed to allocate contiguous frames for LPI pending table");
+            let pendbaser = phys_to_virt(gicr.base + GICR_PENDBASER);
+            // Same attributes as the prop table, the table is zeroed here so PTZ can be set.
+            let pendreg = pend.as_usize() | 0x780 | GICR_PENDBASER_PTZ;
+            debug!(
+                "Setting pendbaser for redistributor {:#x}: {pendbaser:#x} -> {pendreg:#x}",
+                gicr.affinity
+            );
             unsafe {
+                ptr::write_bytes(
+                    phys_to_virt(pend).as_mut_ptr(),
+                    0,
+                    pend_page_num * memory_addr::PAGE_SIZE_4K,
+                );
                 ptr::write_volatile(propbaser.as_mut_ptr_of::<u64>(), propreg as _);
+                ptr::write_volatile(pendbaser.as_mut_ptr_of::<u64>(), pendreg as _);
             }
+            pend_frames.push(pend);
         }
+
+        // Redistributors that only do non-shareable accesses do not snoop the CPU caches.
+        let coherent = host_gicrs().iter().all(|gicr| {
+            perform_mmio_read(gicr.base + GICR_PROPBASER, AccessWidth::Qword)
+                .is_ok_and(|propbaser| propbaser & GICR_BASER_SHAREABILITY_MASK != 0)
+        });
+        debug!("LPI tables are accessed coherently by the redistributors: {coherent}");
+        sync_table_write(
+            phys_to_virt(f),
+            page_num * memory_addr::PAGE_SIZE_4K,
+            coherent,
+        );
+        for &pend in &pend_frames {
+            sync_table_write(
+                phys_to_virt(pend),
+                pend_page_num * memory_addr::PAGE_SIZE_4K,
+                coherent,
+            );
+        }
+
         Self {
             frame: f,
             frame_pages: page_num,
-            _host_gicr_base: host_gicr_base,
+            coherent,
+            pend_frames,
+            pend_frame_pages: pend_page_num,
         }
     }
 
-    fn enable_one_lpi(&self, lpi: usize) {
-        debug!("Enabling one LPI: {lpi}");
-        let addr = self.frame + lpi;
-        let val = 0b1;
+    /// Writes the property byte of the LPI at index `lpi`, without invalidating the copies cached
+    /// by the redistributors.
+    fn write_prop(&self, lpi: usize, prop: u8) -> AxResult {
+        if lpi >= self.frame_pages * memory_addr::PAGE_SIZE_4K {
+            return ax_err!(InvalidInput, "LPI is beyond the LPI prop table");
+        }
 
-        let addr = phys_to_virt(addr);
-        // no priority
+        let addr = phys_to_virt(self.frame + lpi);
         unsafe {
-            ptr::write_volatile(addr.as_mut_ptr_of::<u8>(), val);
+            ptr::write_volatile(addr.as_mut_ptr_of::<u8>(), prop);
         }
+        sync_table_write(addr, 1, self.coherent);
+        Ok(())
+    }
+
+    fn read_prop(&self, lpi: usize) -> AxResult<u8> {
+        if lpi >= self.frame_pages * memory_addr::PAGE_SIZE_4K {
+            return ax_err!(InvalidInput, "LPI is beyond the LPI prop table");
+        }
+
+        Ok(unsafe { ptr::read_volatile(phys_to_virt(self.frame + lpi).as_ptr()) })
+    }
+
+    fn set_prop(&self, lpi: usize, prop: u8) {
+        if let Err(err) = self.write_prop(lpi, prop) {
+            warn!("Ignoring property {prop:#x} of LPI {lpi}: {err:?}");
+        }
+    }
+
+    /// Configures the physical LPI `intid` and makes the redistributors re-read its
+    /// configuration.
+    ///
+    /// `group` is the Group 1 bit of the entry, which is RES1 in GICv3 and always written as 1.
+    pub fn set_lpi_config(&self, intid: u32, enabled: bool, priority: u8, group: bool) -> AxResult {
+        let config = LpiConfig {
+            enabled,
+            priority,
+            group,
+        };
+        self.write_prop(lpi_index(intid)?, config.prop())?;
+        invalidate_host_lpi(intid)
+    }
+
+    /// Disables the physical LPI `intid`, keeping its priority, and makes the redistributors
+    /// re-read its configuration.
+    pub fn disable_lpi(&self, intid: u32) -> AxResult {
+        let lpi = lpi_index(intid)?;
+        self.write_prop(lpi, self.read_prop(lpi)? & !LPI_PROP_ENABLE)?;
+        invalidate_host_lpi(intid)
+    }
+
+    /// Disables every physical LPI, resetting the whole table to its default priority, and makes
+    /// the redistributors re-read it.
+    fn disable_all(&self) -> AxResult {
+        let addr = phys_to_virt(self.frame);
+        let size = self.frame_pages * memory_addr::PAGE_SIZE_4K;
+        unsafe {
+            ptr::write_bytes(addr.as_mut_ptr(), LPI_PROP_RES1, size);
+        }
+        sync_table_write(addr, size, self.coherent);
+        invalidate_host_lpis()
+    }
+
+    /// Configures many physical LPIs at once, then makes the redistributors re-read the whole
+    /// table.
+    ///
+    /// All LPIs are written even if some fail, the first error is returned.
+    pub fn update_lpis(&self, configs: impl IntoIterator<Item = (u32, LpiConfig)>) -> AxResult {
+        let mut result = Ok(());
+        for (intid, config) in configs {
+            if let Err(err) = lpi_index(intid).and_then(|lpi| self.write_prop(lpi, config.prop())) {
+                warn!("Failed to configure LPI {intid}: {err:?}");
+                result = result.and(Err(err));
+            }
+        }
+        result.and(invalidate_host_lpis())
     }
 }
 
-/// Global LPI property table instance.
-pub static LPT: Once<Mutex<LpiPropTable>> = Once::new();
-
-/// Gets or initializes the global LPI property table.
-pub fn get_lpt(
-    host_gicd_typer: u32,
-    host_gicr_base: HostPhysAddr,
-    size_per_gicr: Option<usize>,
-) -> &'static Mutex<LpiPropTable> {
-    if !LPT.is_completed() {
-        LPT.call_once(|| {
-            Mutex::new(LpiPropTable::new(
-                host_gicd_typer,
-                host_gicr_base,
-                size_per_gicr,
-                axvisor_api::host::get_host_cpu_num(),
-            ))
-        });
+/// Configuration of a physical LPI, as held in the LPI configuration table.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LpiConfig {
+    /// Whether the LPI is enabled.
+    pub enabled: bool,
+    /// Priority, only the upper 6 bits are kept.
+    pub priority: u8,
+    /// Group 1 bit, RES1 in GICv3: the table entry always has it set, whatever this says.
+    pub group: bool,
+}
+
+impl LpiConfig {
+    /// Encodes the configuration as a table entry.
+    pub fn prop(&self) -> u8 {
+        // Bit 1 is RES1, LPIs are always Group 1.
+        let mut prop = (self.priority & LPI_PROP_PRIORITY_MASK) | LPI_PROP_RES1;
+        if self.enabled {
+            prop |= LPI_PROP_ENABLE;
+        }
+        prop
+    }
+}
+
+/// Converts an LPI INTID to its index in the LPI configuration table.
+fn lpi_index(intid: u32) -> AxResult<usize> {
+    match intid.checked_sub(LPI_BASE) {
+        Some(lpi) => Ok(lpi as usize),
+        None => ax_err!(InvalidInput, "INTID is not an LPI"),
+    }
+}
+
+/// Makes the host redistributors re-read the configuration of the physical LPI `intid`.
+///
+/// GICR_INVLPIR is used if every redistributor has DirectLPI, otherwise INV is issued on the host
+/// ITS for the event the LPI is mapped from, followed by a SYNC to the redistributor of its
+/// collection. LPIs not mapped yet need no invalidation.
+fn invalidate_host_lpi(intid: u32) -> AxResult {
+    let gicrs = host_gicrs();
+    if gicrs.iter().all(|gicr| gicr.direct_lpi) {
+        for gicr in gicrs {
+            perform_mmio_write(gicr.base + GICR_INVLPIR, AccessWidth::Qword, intid as usize)?;
+            wait_for_gicr_sync(gicr.base)?;
+        }
+        return Ok(());
+    }
+
+    let Some((device_id, event_id, icid)) = host_lpi_event(intid) else {
+        return Ok(());
+    };
+    let mut cmds = vec![[
+        ITS_CMD_INV | (device_id as u64) << 32,
+        event_id as u64,
+        0,
+        0,
+    ]];
+    cmds.extend(host_sync_cmds(&[icid])?);
+    send_host_cmds(&cmds)
+}
+
+/// Makes the host redistributors re-read the whole LPI configuration table.
+///
+/// GICR_INVALLR is used if every redistributor has DirectLPI, otherwise INVALL is issued on the
+/// host ITS for every collection mapped on it, followed by a SYNC to their redistributors.
+fn invalidate_host_lpis() -> AxResult {
+    let gicrs = host_gicrs();
+    if gicrs.iter().all(|gicr| gicr.direct_lpi) {
+        for gicr in gicrs {
+            perform_mmio_write(gicr.base + GICR_INVALLR, AccessWidth::Qword, 0)?;
+            wait_for_gicr_sync(gicr.base)?;
+        }
+        return Ok(());
     }
 
-    LPT.get().unwrap()
+    let icids = host_collections();
+    if icids.is_empty() {
+        return Ok(());
+    }
+    let mut cmds = icids
+        .iter()
+        .map(|&icid| [ITS_CMD_INVALL, 0, icid as u64, 0])
+        .collect::<Vec<_>>();
+    cmds.extend(host_sync_cmds(&icids)?);
+    send_host_cmds(&cmds)
 }
 
-/// Enables a single LPI by updating the property table.
+fn wait_for_gicr_sync(gicr_base: HostPhysAddr) -> AxResult {
+    while perform_mmio_read(gicr_base + GICR_SYNCR, AccessWidth::Dword)? & GICR_SYNCR_BUSY != 0 {
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+/// Global LPI property table instance, `None` until [`init_lpt`].
+///
+/// The host redistributors point at the table and its pending tables once LPIs are enabled, and
+/// EnableLPIs may not be cleared on every implementation, so the table is never freed.
+///
+/// The table is updated through `&self`, the lock only guards its lifetime. Updates may send
+/// commands to the host ITS, whose command queue lock is also held while the table is updated on
+/// behalf of guest commands, so updaters must never take it exclusively.
+pub static LPT: RwLock<Option<LpiPropTable>> = RwLock::new(None);
+
+/// Initializes the global LPI property table, if not done yet.
+pub fn init_lpt(host_gicd_typer: u32) {
+    if LPT.read().is_some() {
+        return;
+    }
+    let mut lpt = LPT.write();
+    if lpt.is_none() {
+        *lpt = Some(LpiPropTable::new(host_gicd_typer));
+    }
+}
+
+/// Disables every LPI in the global LPI property table, which stays allocated.
+///
+/// No VM may use the LPIs anymore. Pending bits already latched in the host pending tables are
+/// kept, they are only delivered if the LPI is enabled again.
+pub fn reset_lpt() -> AxResult {
+    with_lpt(|lpt| lpt.disable_all())
+}
+
+/// Runs `f` on the global LPI property table, failing with `BadState` if it is not initialized.
+pub fn with_lpt<R>(f: impl FnOnce(&LpiPropTable) -> AxResult<R>) -> AxResult<R> {
+    match LPT.read().as_ref() {
+        Some(lpt) => f(lpt),
+        None => ax_err!(BadState, "LPI property table is not initialized"),
+    }
+}
+
+/// Enables a single LPI with priority 0 by updating the property table.
+///
+/// `lpi` is an index into the table, i.e. the INTID minus 8192. Kept for existing callers,
+/// [`set_lpi_prop`] sets the whole property byte.
 pub fn enable_one_lpi(lpi: usize) {
-    let lpt = get_lpt(
-        crate::api_reexp::read_vgicd_typer(),
-        crate::api_reexp::get_host_gicr_base(),
-        None, // Use default size
-    );
-    let lpt = lpt.lock();
-    lpt.enable_one_lpi(lpi);
+    debug!("Enabling one LPI: {lpi}");
+    set_lpi_prop(lpi, LPI_PROP_ENABLE | LPI_PROP_RES1);
+}
+
+/// Sets the property byte (priority and enable) of a single LPI in the property table.
+///
+/// `lpi` is an index into the table, i.e. the INTID minus 8192.
+pub fn set_lpi_prop(lpi: usize, prop: u8) {
+    if let Err(err) = with_lpt(|lpt| {
+        lpt.set_prop(lpi, prop);
+        Ok(())
+    }) {
+        warn!(
+            "Failed to set LPI {} property: {err:?}",
+            lpi + LPI_BASE as usize
+        );
+    }
 }
@@ -0,0 +1,633 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Unlike [`Gits`](crate::v3::gits::Gits), which forwards guest commands to the host ITS, the software
+//! ITS executes them itself. Its device table, ITTs and collection table live in hypervisor
+//! memory, and the LPIs it translates are purely virtual, so it needs no host ITS at all.
+//!
+//! GITS_BASERn are not implemented, so guests allocate no tables for it. GITS_TYPER.PTA is 0:
+//! MAPC and MOVALL name redistributors by vCPU ID.
+
+extern crate alloc;
+
+use alloc::collections::{BTreeMap, BTreeSet};
+
+use axaddrspace::{device::AccessWidth, GuestPhysAddr, GuestPhysAddrRange};
+use axdevice_base::{BaseDeviceOps, EmuDeviceType};
+use axerrno::{ax_err, AxResult};
+use axvisor_api::vmm::{VCpuId, VMId};
+use log::{debug, warn};
+use spin::{Mutex, MutexGuard};
+
+use super::{
+    gits::{ring_ptr_update, BYTES_PER_CMD, DEFAULT_GITS_SIZE, QWORD_PER_CMD},
+    lpi,
+    registers::*,
+    utils::{read_guest, width_mask},
+};
+
+/// Number of DeviceID bits of the software ITS.
+const DEVICE_ID_BITS: usize = 16;
+/// Number of EventID bits of the software ITS.
+const EVENT_ID_BITS: usize = 16;
+/// Maximum number of devices a VM may map at once. Device tables and ITTs live in hypervisor
+/// memory, the limits keep the guest from growing them without bound.
+const MAX_DEVICES: usize = 256;
+/// Maximum number of events a VM may map at once, over all its devices.
+const MAX_EVENTS: usize = 8192;
+/// Size of an ITT entry reported in GITS_TYPER. ITTs are not kept in guest memory, but guests
+/// still size the memory they pass to MAPD with it.
+const ITT_ENTRY_SIZE: usize = 8;
+
+/// An event of a device, mapped by MAPTI or MAPI.
+#[derive(Clone, Copy, Debug)]
+struct Ite {
+    /// The virtual LPI the event is translated to.
+    intid: u32,
+    /// The collection the LPI is routed through.
+    icid: u16,
+}
+
+/// A device mapped by MAPD, with its interrupt translation table.
+struct VitsDevice {
+    /// Number of EventID bits of the device.
+    event_bits: u32,
+    itt: BTreeMap<u32, Ite>,
+}
+
+/// State of a software ITS.
+#[derive(Default)]
+struct VitsState {
+    ctlr: usize,
+    cbaser: usize,
+    creadr: usize,
+    cwriter: usize,
+    /// Device table: DeviceID to device.
+    devices: BTreeMap<u32, VitsDevice>,
+    /// Number of events mapped over all devices.
+    events: usize,
+    /// Collection table: ICID to the vCPU of the target redistributor.
+    collections: BTreeMap<u16, VCpuId>,
+    /// vCPUs whose pending virtual LPIs are to be injected once the state lock is dropped.
+    to_flush: BTreeSet<VCpuId>,
+}
+
+impl VitsState {
+    /// Looks up the event `event_id` of the device `device_id`.
+    fn ite(&self, device_id: u32, event_id: u32) -> AxResult<Ite> {
+        let Some(device) = self.devices.get(&device_id) else {
+            return ax_err!(NotFound, "Device is not mapped");
+        };
+        match device.itt.get(&event_id) {
+            Some(&ite) => Ok(ite),
+            None => ax_err!(NotFound, "Event is not mapped"),
+        }
+    }
+
+    /// Looks up the vCPU an event is routed to.
+    fn target(&self, device_id: u32, event_id: u32) -> AxResult<(Ite, VCpuId)> {
+        let ite = self.ite(device_id, event_id)?;
+        match self.collections.get(&ite.icid) {
+            Some(&vcpu_id) => Ok((ite, vcpu_id)),
+            None => ax_err!(NotFound, "Collection is not mapped"),
+        }
+    }
+
+    /// Translates an event into its virtual LPI and makes it pending on the target vCPU, as a
+    /// GITS_TRANSLATER write or an INT command does.
+    fn translate(&mut self, vm_id: VMId, device_id: u32, event_id: u32) -> AxResult {
+        let (ite, vcpu_id) = self.target(device_id, event_id)?;
+        lpi::mark_virtual_pending(vm_id, vcpu_id, ite.intid)?;
+        self.to_flush.insert(vcpu_id);
+        Ok(())
+    }
+
+    /// Executes one command from the guest's command queue.
+    fn execute(&mut self, vm_id: VMId, vcpu_num: usize, cmd: [u64; QWORD_PER_CMD]) -> AxResult {
+        let code = cmd[0] & 0xff;
+        let device_id = (cmd[0] >> 32) as u32;
+        let event_id = cmd[1] as u32;
+        let icid = cmd[2] as u16;
+        let valid = cmd[2] & (1 << 63) != 0;
+
+        match code {
+            ITS_CMD_MAPD => {
+                if device_id as usize >> DEVICE_ID_BITS != 0 {
+                    return ax_err!(InvalidInput, "DeviceID out of range");
+                }
+                let event_bits = (cmd[1] & 0x1f) as u32 + 1;
+                if event_bits as usize > EVENT_ID_BITS {
+                    return ax_err!(InvalidInput, "ITT size out of range");
+                }
+
+                debug!("vITS MAPD device {device_id:#x} event bits {event_bits} valid {valid}");
+                if valid
+                    && !self.devices.contains_key(&device_id)
+                    && self.devices.len() >= MAX_DEVICES
+                {
+                    return ax_err!(NoMemory, "Too many devices mapped");
+                }
+                let old = if valid {
+                    self.devices.insert(
+                        device_id,
+                        VitsDevice {
+                            event_bits,
+                            itt: BTreeMap::new(),
+                        },
+                    )
+                } else {
+                    self.devices.remove(&device_id)
+                };
+                // The events of the old ITT are gone, and so is their pending state.
+                if let Some(old) = old {
+                    self.events -= old.itt.len();
+                    for ite in old.itt.values() {
+                        if let Some(&vcpu_id) = self.collections.get(&ite.icid) {
+                            lpi::clear_virtual_pending(vm_id, vcpu_id, ite.intid);
+                        }
+                    }
+                }
+            }
+            ITS_CMD_MAPC => {
+                let rd_base = ((cmd[2] >> 16) & 0x7_ffff_ffff) as usize;
+                debug!("vITS MAPC icid {icid:#x} -> vCPU {rd_base} valid {valid}");
+                if !valid {
+                    self.collections.remove(&icid);
+                } else if rd_base < vcpu_num {
+                    self.collections.insert(icid, rd_base);
+                } else {
+                    return ax_err!(InvalidInput, "RDbase is not a vCPU of the VM");
+                }
+            }
+            ITS_CMD_MAPTI | ITS_CMD_MAPI => {
+                let intid = if code == ITS_CMD_MAPTI {
+                    (cmd[1] >> 32) as u32
+                } else {
+                    event_id
+                };
+                debug!(
+                    "vITS MAPTI device {device_id:#x} event {event_id:#x} -> icid {icid:#x} intid {intid:#x}"
+                );
+                if !lpi::in_lpi_space(vm_id, intid) {
+                    return ax_err!(InvalidInput, "INTID is not in the LPI space of the VM");
+                }
+                let Some(device) = self.devices.get_mut(&device_id) else {
+                    return ax_err!(NotFound, "Device is not mapped");
+                };
+                if event_id >> device.event_bits != 0 {
+                    return ax_err!(InvalidInput, "EventID out of range");
+                }
+                if !device.itt.contains_key(&event_id) {
+                    if self.events >= MAX_EVENTS {
+                        return ax_err!(NoMemory, "Too many events mapped");
+                    }
+                    self.events += 1;
+                }
+                device.itt.insert(event_id, Ite { intid, icid });
+            }
+            ITS_CMD_MOVI => {
+                let (ite, from) = self.target(device_id, event_id)?;
+                let Some(&to) = self.collections.get(&icid) else {
+                    return ax_err!(NotFound, "Collection is not mapped");
+                };
+                debug!("vITS MOVI device {device_id:#x} event {event_id:#x} -> icid {icid:#x}");
+                if lpi::shift_virtual_pending(vm_id, from, to, Some(&[ite.intid])) {
+                    self.to_flush.insert(to);
+                }
+                if let Some(device) = self.devices.get_mut(&device_id) {
+                    device.itt.insert(event_id, Ite { icid, ..ite });
+                }
+            }
+            ITS_CMD_DISCARD => {
+                let ite = self.ite(device_id, event_id)?;
+                if let Some(&vcpu_id) = self.collections.get(&ite.icid) {
+                    lpi::clear_virtual_pending(vm_id, vcpu_id, ite.intid);
+                }
+                if let Some(device) = self.devices.get_mut(&device_id) {
+                    if device.itt.remove(&event_id).is_some() {
+                        self.events -= 1;
+                    }
+                }
+            }
+            ITS_CMD_INT => self.translate(vm_id, device_id, event_id)?,
+            ITS_CMD_CLEAR => {
+                let (ite, vcpu_id) = self.target(device_id, event_id)?;
+                lpi::clear_virtual_pending(vm_id, vcpu_id, ite.intid);
+            }
+            // The guest's configuration table is read whenever a pending LPI is injected, so
+            // there is no cached configuration to drop, only LPIs it may have just enabled.
+            ITS_CMD_INV => {
+                let (_, vcpu_id) = self.target(device_id, event_id)?;
+                self.to_flush.insert(vcpu_id);
+            }
+            ITS_CMD_INVALL => {
+                let Some(&vcpu_id) = self.collections.get(&icid) else {
+                    return ax_err!(NotFound, "Collection is not mapped");
+                };
+                self.to_flush.insert(vcpu_id);
+            }
+            // Commands take effect immediately.
+            ITS_CMD_SYNC => {}
+            ITS_CMD_MOVALL => {
+                let from = ((cmd[2] >> 16) & 0x7_ffff_ffff) as usize;
+                let to = ((cmd[3] >> 16) & 0x7_ffff_ffff) as usize;
+                if from >= vcpu_num || to >= vcpu_num {
+                    return ax_err!(InvalidInput, "RDbase is not a vCPU of the VM");
+                }
+                debug!("vITS MOVALL vCPU {from} -> vCPU {to}");
+                if lpi::shift_virtual_pending(vm_id, from, to, None) {
+                    self.to_flush.insert(to);
+                }
+            }
+            _ => return ax_err!(Unsupported, "Unsupported ITS command"),
+        }
+        Ok(())
+    }
+
+    /// Executes the commands the guest queued up to `cwriter`, unless the ITS is disabled.
+    /// `read_cmd` reads the command at a guest physical address.
+    ///
+    /// Invalid commands are ignored. A command that cannot be read stalls the queue, as reported
+    /// by GITS_CREADR.Stalled, until the guest retries with GITS_CWRITER.Retry.
+    fn process_cmds(
+        &mut self,
+        vm_id: VMId,
+        vcpu_num: usize,
+        read_cmd: impl Fn(GuestPhysAddr) -> AxResult<[u64; QWORD_PER_CMD]>,
+    ) {
+        if self.ctlr & GITS_CTLR_ENABLED == 0 || self.cbaser & GITS_BASER_VALID == 0 {
+            return;
+        }
+
+        let base = self.cbaser & GITS_CBASER_PA_MASK;
+        let size = ((self.cbaser & GITS_CBASER_SIZE_MASK) + 1) * 0x1000;
+        let cwriter = self.cwriter & GITS_CQ_OFFSET_MASK;
+        if cwriter >= size {
+            warn!("VM {vm_id} vITS CWRITER {cwriter:#x} is beyond its {size:#x} bytes queue");
+            return;
+        }
+
+        let mut creadr = self.creadr & GITS_CQ_OFFSET_MASK;
+        while creadr != cwriter {
+            let gpa = GuestPhysAddr::from_usize(base + creadr);
+            let cmd = match read_cmd(gpa) {
+                Ok(cmd) => cmd,
+                Err(err) => {
+                    warn!("VM {vm_id} vITS command at {gpa:?} stalls the queue: {err:?}");
+                    self.creadr = creadr | GITS_CREADR_STALLED;
+                    return;
+                }
+            };
+            if let Err(err) = self.execute(vm_id, vcpu_num, cmd) {
+                warn!("VM {vm_id} vITS ignoring command {cmd:#x?}: {err:?}");
+            }
+
+            creadr = ring_ptr_update(creadr + BYTES_PER_CMD, size);
+        }
+        self.creadr = creadr;
+    }
+}
+
+/// Software GICv3 Interrupt Translation Service of a VM.
+pub struct VirtualIts {
+    /// The address of the ITS in the guest physical address space.
+    pub addr: GuestPhysAddr,
+    /// Size of the ITS region.
+    pub size: usize,
+    /// The VM this ITS belongs to.
+    pub vm_id: VMId,
+    /// Number of vCPUs of the VM, i.e. of valid MAPC targets.
+    pub vcpu_num: usize,
+
+    state: Mutex<VitsState>,
+}
+
+impl VirtualIts {
+    /// Creates a new software ITS for a VM with `vcpu_num` vCPUs.
+    pub fn new(addr: GuestPhysAddr, size: Option<usize>, vm_id: VMId, vcpu_num: usize) -> Self {
+        Self {
+            addr,
+            size: size.unwrap_or(DEFAULT_GITS_SIZE),
+            vm_id,
+            vcpu_num,
+            state: Mutex::new(VitsState::default()),
+        }
+    }
+
+    /// Signals the event `event_id` of the device `device_id`, as a write of the device to
+    /// GITS_TRANSLATER does. The event's virtual LPI is made pending on its target vCPU.
+    ///
+    /// Fails with `BadState` if the ITS is disabled, and with `NotFound` if the event or its
+    /// collection is not mapped.
+    pub fn signal_msi(&self, device_id: u32, event_id: u32) -> AxResult {
+        let mut state = self.state.lock();
+        if state.ctlr & GITS_CTLR_ENABLED == 0 {
+            return ax_err!(BadState, "ITS is disabled");
+        }
+        let result = state.translate(self.vm_id, device_id, event_id);
+        self.unlock_and_flush(state);
+        result
+    }
+
+    /// Drops the state lock, then injects the virtual LPIs the executed commands made
+    /// deliverable.
+    fn unlock_and_flush(&self, mut state: MutexGuard<VitsState>) {
+        let vcpus = core::mem::take(&mut state.to_flush);
+        drop(state);
+        for vcpu_id in vcpus {
+            lpi::flush_virtual_pending(self.vm_id, vcpu_id);
+        }
+    }
+
+    fn typer(&self) -> usize {
+        GITS_TYPER_PHYSICAL
+            | (ITT_ENTRY_SIZE - 1) << GITS_TYPER_ITT_ENTRY_SIZE_SHIFT
+            | (EVENT_ID_BITS - 1) << GITS_TYPER_IDBITS_SHIFT
+            | (DEVICE_ID_BITS - 1) << GITS_TYPER_DEVBITS_SHIFT
+            | self.vcpu_num.min(0xff) << GITS_TYPER_HCC_SHIFT
+    }
+}
+
+impl BaseDeviceOps<GuestPhysAddrRange> for VirtualIts {
+    fn emu_type(&self) -> EmuDeviceType {
+        EmuDeviceType::InterruptController
+    }
+
+    fn address_range(&self) -> GuestPhysAddrRange {
+        GuestPhysAddrRange::from_start_size(self.addr, self.size)
+    }
+
+    fn handle_read(&self, addr: GuestPhysAddr, width: AccessWidth) -> AxResult<usize> {
+        let reg = addr - self.addr;
+        let state = self.state.lock();
+        let value = match reg {
+            // Commands are executed synchronously, so the ITS is always quiescent.
+            GITS_CTRL => state.ctlr | GITS_CTLR_QUIESCENT,
+            GITS_IIDR => 0x43b,
+            GITS_TYPER => self.typer(),
+            GITS_CBASER => state.cbaser,
+            GITS_CWRITER => state.cwriter,
+            GITS_CREADR => state.creadr,
+            GITS_PIDR2 => 0x3b,
+            _ => 0,
+        };
+        debug!("vITS {} read reg {reg:#x} -> {value:#x}", self.vm_id);
+        Ok(value & width_mask(width))
+    }
+
+    fn handle_write(&self, addr: GuestPhysAddr, width: AccessWidth, val: usize) -> AxResult {
+        let reg = addr - self.addr;
+        debug!(
+            "vITS {} write reg {reg:#x} width {width:?} value {val:#x}",
+            self.vm_id
+        );
+
+        let mut state = self.state.lock();
+        match reg {
+            GITS_CTRL => {
+                state.ctlr = val & GITS_CTLR_ENABLED;
+                state.process_cmds(self.vm_id, self.vcpu_num, |gpa| read_guest(self.vm_id, gpa));
+            }
+            GITS_CBASER => {
+                // GITS_CBASER may only be written while the ITS is disabled, and resets CREADR.
+                if state.ctlr & GITS_CTLR_ENABLED == 0 {
+                    state.cbaser = val;
+                    state.creadr = 0;
+                }
+            }
+            GITS_CWRITER => {
+                state.cwriter = val & GITS_CQ_OFFSET_MASK;
+                if state.creadr & GITS_CREADR_STALLED != 0 && val & GITS_CWRITER_RETRY == 0 {
+                    return Ok(());
+                }
+                state.creadr &= !GITS_CREADR_STALLED;
+                state.process_cmds(self.vm_id, self.vcpu_num, |gpa| read_guest(self.vm_id, gpa));
+            }
+            // Writes from CPUs carry no DeviceID, they are taken as coming from device 0.
+            GITS_TRANSLATER => {
+                let event_id = (val & 0xffff_ffff) as u32;
+                if state.ctlr & GITS_CTLR_ENABLED == 0 {
+                    return Ok(());
+                }
+                if let Err(err) = state.translate(self.vm_id, 0, event_id) {
+                    warn!("vITS {} dropping event {event_id:#x}: {err:?}", self.vm_id);
+                }
+            }
+            _ => {}
+        }
+        self.unlock_and_flush(state);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::RefCell;
+
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    /// A guest LPI configuration table with 16 bits of INTID.
+    const PROPBASER: usize = 0x8000_0000 | 15;
+
+    fn mapd(device_id: u32, event_bits: u64, valid: bool) -> [u64; QWORD_PER_CMD] {
+        [
+            ITS_CMD_MAPD | (device_id as u64) << 32,
+            event_bits - 1,
+            (valid as u64) << 63,
+            0,
+        ]
+    }
+
+    fn mapc(icid: u16, vcpu_id: VCpuId) -> [u64; QWORD_PER_CMD] {
+        [
+            ITS_CMD_MAPC,
+            0,
+            1 << 63 | (vcpu_id as u64) << 16 | icid as u64,
+            0,
+        ]
+    }
+
+    fn mapti(device_id: u32, event_id: u32, intid: u32, icid: u16) -> [u64; QWORD_PER_CMD] {
+        [
+            ITS_CMD_MAPTI | (device_id as u64) << 32,
+            (intid as u64) << 32 | event_id as u64,
+            icid as u64,
+            0,
+        ]
+    }
+
+    fn int(device_id: u32, event_id: u32) -> [u64; QWORD_PER_CMD] {
+        [
+            ITS_CMD_INT | (device_id as u64) << 32,
+            event_id as u64,
+            0,
+            0,
+        ]
+    }
+
+    #[test]
+    fn mapd_device_limit() {
+        let vm_id = 101;
+        let mut state = VitsState::default();
+        for device_id in 0..MAX_DEVICES as u32 {
+            state.execute(vm_id, 1, mapd(device_id, 1, true)).unwrap();
+        }
+        let extra = MAX_DEVICES as u32;
+        assert!(state.execute(vm_id, 1, mapd(extra, 1, true)).is_err());
+
+        // Remapping a device takes no new slot, unmapping one frees it.
+        state.execute(vm_id, 1, mapd(0, 2, true)).unwrap();
+        state.execute(vm_id, 1, mapd(0, 1, false)).unwrap();
+        state.execute(vm_id, 1, mapd(extra, 1, true)).unwrap();
+        assert_eq!(state.devices.len(), MAX_DEVICES);
+
+        assert!(state
+            .execute(vm_id, 1, mapd(1 << DEVICE_ID_BITS, 1, true))
+            .is_err());
+        assert!(state
+            .execute(vm_id, 1, mapd(1, EVENT_ID_BITS as u64 + 1, true))
+            .is_err());
+    }
+
+    #[test]
+    fn mapti_event_limit() {
+        let vm_id = 102;
+        lpi::set_propbaser(vm_id, PROPBASER);
+        let mut state = VitsState::default();
+        state.execute(vm_id, 1, mapd(0, 16, true)).unwrap();
+        state.execute(vm_id, 1, mapd(1, 16, true)).unwrap();
+        for event_id in 0..MAX_EVENTS as u32 {
+            state
+                .execute(vm_id, 1, mapti(0, event_id, LPI_BASE + event_id, 0))
+                .unwrap();
+        }
+        // The limit is over all devices.
+        assert!(state.execute(vm_id, 1, mapti(1, 0, LPI_BASE, 0)).is_err());
+        // Remapping an event takes no new slot.
+        state
+            .execute(vm_id, 1, mapti(0, 0, LPI_BASE + 1, 0))
+            .unwrap();
+        assert_eq!(state.events, MAX_EVENTS);
+
+        // Discarding an event frees its slot.
+        state.execute(vm_id, 1, [ITS_CMD_DISCARD, 0, 0, 0]).unwrap();
+        state.execute(vm_id, 1, mapti(1, 0, LPI_BASE, 0)).unwrap();
+        assert_eq!(state.events, MAX_EVENTS);
+
+        // INTIDs must be in the LPI space of the VM.
+        state.execute(vm_id, 1, mapd(0, 1, true)).unwrap();
+        assert!(state
+            .execute(vm_id, 1, mapti(0, 0, LPI_BASE - 1, 0))
+            .is_err());
+        assert!(state.execute(vm_id, 1, mapti(0, 0, 1 << 16, 0)).is_err());
+        // EventIDs must fit the ITT of the device.
+        assert!(state.execute(vm_id, 1, mapti(0, 2, LPI_BASE, 0)).is_err());
+    }
+
+    #[test]
+    fn mapd_unmap_drops_events() {
+        let vm_id = 103;
+        lpi::set_propbaser(vm_id, PROPBASER);
+        let mut state = VitsState::default();
+        state.execute(vm_id, 1, mapc(0, 0)).unwrap();
+        state.execute(vm_id, 1, mapd(0, 2, true)).unwrap();
+        state.execute(vm_id, 1, mapti(0, 0, LPI_BASE, 0)).unwrap();
+        state
+            .execute(vm_id, 1, mapti(0, 1, LPI_BASE + 1, 0))
+            .unwrap();
+        state.execute(vm_id, 1, int(0, 0)).unwrap();
+        assert!(lpi::is_virtual_pending(vm_id, 0, LPI_BASE));
+        assert_eq!(state.events, 2);
+
+        state.execute(vm_id, 1, mapd(0, 2, false)).unwrap();
+        assert_eq!(state.events, 0);
+        assert!(!lpi::is_virtual_pending(vm_id, 0, LPI_BASE));
+        assert!(state.execute(vm_id, 1, int(0, 0)).is_err());
+
+        // Remapping the device starts from an empty ITT.
+        state.execute(vm_id, 1, mapd(0, 2, true)).unwrap();
+        assert!(state.execute(vm_id, 1, int(0, 1)).is_err());
+    }
+
+    #[test]
+    fn movi_movall_move_pending() {
+        let vm_id = 104;
+        lpi::set_propbaser(vm_id, PROPBASER);
+        let mut state = VitsState::default();
+        state.execute(vm_id, 2, mapc(0, 0)).unwrap();
+        state.execute(vm_id, 2, mapc(1, 1)).unwrap();
+        state.execute(vm_id, 2, mapd(0, 2, true)).unwrap();
+        state.execute(vm_id, 2, mapti(0, 0, LPI_BASE, 0)).unwrap();
+        state
+            .execute(vm_id, 2, mapti(0, 1, LPI_BASE + 1, 0))
+            .unwrap();
+        state.execute(vm_id, 2, int(0, 0)).unwrap();
+        state.execute(vm_id, 2, int(0, 1)).unwrap();
+        state.to_flush.clear();
+
+        state.execute(vm_id, 2, [ITS_CMD_MOVI, 0, 1, 0]).unwrap();
+        assert!(!lpi::is_virtual_pending(vm_id, 0, LPI_BASE));
+        assert!(lpi::is_virtual_pending(vm_id, 1, LPI_BASE));
+        assert!(lpi::is_virtual_pending(vm_id, 0, LPI_BASE + 1));
+        assert!(state.to_flush.contains(&1));
+        // The event now targets the new collection.
+        assert_eq!(state.ite(0, 0).unwrap().icid, 1);
+
+        state
+            .execute(vm_id, 2, [ITS_CMD_MOVALL, 0, 0, 1 << 16])
+            .unwrap();
+        assert!(!lpi::is_virtual_pending(vm_id, 0, LPI_BASE + 1));
+        assert!(lpi::is_virtual_pending(vm_id, 1, LPI_BASE + 1));
+        assert!(lpi::is_virtual_pending(vm_id, 1, LPI_BASE));
+
+        // Redistributors must be vCPUs of the VM.
+        assert!(state
+            .execute(vm_id, 2, [ITS_CMD_MOVALL, 0, 1 << 16, 2 << 16])
+            .is_err());
+    }
+
+    #[test]
+    fn cwriter_wraps() {
+        let vm_id = 105;
+        let base = 0x9000_0000;
+        let mut state = VitsState {
+            ctlr: GITS_CTLR_ENABLED,
+            // A one-page queue.
+            cbaser: GITS_BASER_VALID | base,
+            creadr: 0x1000 - 2 * BYTES_PER_CMD,
+            cwriter: BYTES_PER_CMD,
+            ..Default::default()
+        };
+        let read = RefCell::new(Vec::new());
+        state.process_cmds(vm_id, 1, |gpa| {
+            read.borrow_mut().push(gpa.as_usize() - base);
+            Ok([ITS_CMD_SYNC, 0, 0, 0])
+        });
+        assert_eq!(*read.borrow(), [0xfc0, 0xfe0, 0]);
+        assert_eq!(state.creadr, BYTES_PER_CMD);
+
+        // A command that cannot be read stalls the queue at its offset.
+        state.creadr = 0x1000 - BYTES_PER_CMD;
+        state.process_cmds(vm_id, 1, |gpa| {
+            if gpa.as_usize() == base {
+                ax_err!(BadAddress, "Unmapped command")
+            } else {
+                Ok([ITS_CMD_SYNC, 0, 0, 0])
+            }
+        });
+        assert_eq!(state.creadr, GITS_CREADR_STALLED);
+    }
+}
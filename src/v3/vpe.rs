@@ -0,0 +1,382 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Each vCPU of a VM using GICv4 is backed by a vPE. MSIs of passthrough devices are mapped to
+//! virtual LPIs of the vPEs with VMAPTI, and delivered by the GIC straight into the vCPU while
+//! its vPE is resident on the redistributor of the physical CPU it runs on.
+//!
+//! Only the GICv4.0 vPE interface is implemented. GICv4.1 hosts are refused, VMs on them use
+//! forwarded physical LPIs.
+
+extern crate alloc;
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    vec::Vec,
+};
+use core::ptr;
+
+use axaddrspace::device::AccessWidth;
+use axerrno::{ax_err, AxResult};
+use axvisor_api::{
+    memory::phys_to_virt,
+    vmm::{VCpuId, VMId},
+};
+use log::{debug, warn};
+use memory_addr::{PhysAddr, PAGE_SIZE_4K};
+use spin::Mutex;
+
+use super::{
+    gits::{read_host_gits, send_host_cmds},
+    host_gicr::{find_host_gicr, host_gicrs, HostGicr},
+    registers::*,
+    utils::{perform_mmio_read, perform_mmio_write},
+};
+
+/// Doorbell INTID meaning no doorbell.
+const NO_DOORBELL: u64 = 1023;
+
+/// Smallest number of virtual INTID bits, enough for the first LPI.
+const MIN_ID_BITS: u32 = 14;
+
+/// A virtual PE, backing one vCPU.
+struct VPe {
+    /// vPE ID used in ITS commands.
+    id: u16,
+    /// Virtual LPI pending table.
+    vpt: PhysAddr,
+    /// The host redistributor the vPE is mapped to with VMAPP.
+    mapped_on: Option<&'static HostGicr>,
+    /// Whether the vPE is resident on `mapped_on`.
+    resident: bool,
+}
+
+/// The vPEs of a VM.
+struct VmVpes {
+    /// Number of virtual INTID bits.
+    id_bits: u32,
+    /// Virtual LPI configuration table, shared by all vPEs of the VM.
+    vprop: PhysAddr,
+    vprop_pages: usize,
+    vpt_pages: usize,
+    /// vPEs indexed by vCPU ID.
+    vpes: Vec<VPe>,
+    /// Collections mapped by the guest with MAPC, ICID to vCPU.
+    collections: BTreeMap<u16, VCpuId>,
+}
+
+impl VmVpes {
+    fn vpe_of_collection(&self, icid: u16) -> Option<u16> {
+        let vcpu_id = *self.collections.get(&icid)?;
+        self.vpes.get(vcpu_id).map(|vpe| vpe.id)
+    }
+}
+
+impl Drop for VmVpes {
+    fn drop(&mut self) {
+        let mut ids = VPE_IDS.lock();
+        for vpe in &self.vpes {
+            axvisor_api::memory::dealloc_contiguous_frames(vpe.vpt, self.vpt_pages);
+            ids.remove(&vpe.id);
+        }
+        axvisor_api::memory::dealloc_contiguous_frames(self.vprop, self.vprop_pages);
+    }
+}
+
+/// vPEs of all VMs using GICv4.
+static VM_VPES: Mutex<BTreeMap<VMId, VmVpes>> = Mutex::new(BTreeMap::new());
+/// vPE IDs in use.
+static VPE_IDS: Mutex<BTreeSet<u16>> = Mutex::new(BTreeSet::new());
+
+/// Creates a vPE for each of the `vcpu_num` vCPUs of a VM, with `id_bits` bits of virtual
+/// INTIDs.
+///
+/// From then on the ITS commands of the VM map its MSIs to virtual LPIs. Fails with
+/// `Unsupported` if the host ITS or a host redistributor lacks GICv4 support.
+///
+/// Only GICv4.0 is supported. GICv4.1 (GITS_TYPER.VMAPP, GICR_TYPER.RVPEID) changes the VMAPP
+/// command, GICR_VPENDBASER and GICR_VPROPBASER and needs a vPE configuration table shared by
+/// the ITS and the redistributors, none of which is implemented: this fails with `Unsupported`
+/// on such hosts too, and the VM keeps using physical LPIs forwarded by the hypervisor.
+pub fn create_vm_vpes(vm_id: VMId, vcpu_num: usize, id_bits: u32) -> AxResult {
+    if VM_VPES.lock().contains_key(&vm_id) {
+        return ax_err!(AlreadyExists, "The VM already has vPEs");
+    }
+    let gits_typer = read_host_gits(GITS_TYPER)?;
+    if gits_typer & GITS_TYPER_VIRTUAL == 0 || host_gicrs().iter().any(|gicr| !gicr.vlpis) {
+        return ax_err!(Unsupported, "Host GIC does not support GICv4");
+    }
+    if gits_typer & GITS_TYPER_VMAPP != 0 || host_gicrs().iter().any(|gicr| gicr.rvpeid) {
+        warn!("VM {vm_id} gets no vPEs: GICv4.1 hosts are not supported, MSIs stay forwarded");
+        return ax_err!(
+            Unsupported,
+            "Host GIC implements GICv4.1, only GICv4.0 is supported"
+        );
+    }
+
+    let id_bits = id_bits.clamp(MIN_ID_BITS, 32);
+    let vprop_pages = ((1usize << id_bits) - LPI_BASE as usize).div_ceil(PAGE_SIZE_4K);
+    let mut vm = VmVpes {
+        id_bits,
+        vprop: alloc_zeroed(vprop_pages, 0)?,
+        vprop_pages,
+        vpt_pages: (1usize << id_bits).div_ceil(8 * PAGE_SIZE_4K),
+        vpes: Vec::with_capacity(vcpu_num),
+        collections: BTreeMap::new(),
+    };
+    for _ in 0..vcpu_num {
+        let Some(id) = alloc_vpe_id() else {
+            return ax_err!(NoMemory, "Out of vPE IDs");
+        };
+        // The VPT must be 64 KiB aligned.
+        let vpt = match alloc_zeroed(vm.vpt_pages, 4) {
+            Ok(vpt) => vpt,
+            Err(err) => {
+                VPE_IDS.lock().remove(&id);
+                return Err(err);
+            }
+        };
+        vm.vpes.push(VPe {
+            id,
+            vpt,
+            mapped_on: None,
+            resident: false,
+        });
+    }
+
+    debug!("VM {vm_id} created {vcpu_num} vPEs with {id_bits} INTID bits");
+    VM_VPES.lock().insert(vm_id, vm);
+    Ok(())
+}
+
+/// Unmaps and frees the vPEs of a VM.
+///
+/// The vCPUs must have been put with [`vpe_put`].
+pub fn destroy_vm_vpes(vm_id: VMId) -> AxResult {
+    let Some(vm) = VM_VPES.lock().remove(&vm_id) else {
+        return Ok(());
+    };
+
+    let pta = host_pta()?;
+    let cmds = vm
+        .vpes
+        .iter()
+        .filter_map(|vpe| Some(vmapp_cmd(vpe, vm.id_bits, vpe.mapped_on?, pta, false)))
+        .collect::<Vec<_>>();
+    send_host_cmds(&cmds)
+}
+
+/// Checks if a VM uses GICv4 vPEs.
+pub fn has_vpes(vm_id: VMId) -> bool {
+    VM_VPES.lock().contains_key(&vm_id)
+}
+
+/// Makes the vPE of a vCPU resident on the redistributor of the physical CPU with MPIDR_EL1 value
+/// `host_mpidr`, to be called when the vCPU is loaded.
+///
+/// The vPE is mapped to, or moved to, that redistributor on the host ITS first.
+pub fn vpe_load(vm_id: VMId, vcpu_id: VCpuId, host_mpidr: u64) -> AxResult {
+    let Some(gicr) = find_host_gicr(host_mpidr) else {
+        return ax_err!(NotFound, "No host redistributor for the given MPIDR");
+    };
+    let pta = host_pta()?;
+
+    // The ITS commands are sent without the vPE lock held, guest commands take it under the
+    // command queue lock.
+    let cmd = {
+        let vms = VM_VPES.lock();
+        let Some(vm) = vms.get(&vm_id) else {
+            return ax_err!(NotFound, "The VM has no vPEs");
+        };
+        let Some(vpe) = vm.vpes.get(vcpu_id) else {
+            return ax_err!(InvalidInput, "vCPU ID is out of range");
+        };
+        match vpe.mapped_on {
+            Some(mapped_on) if mapped_on.base == gicr.base => None,
+            Some(_) => Some(vmovp_cmd(vpe, gicr, pta)?),
+            None => Some(vmapp_cmd(vpe, vm.id_bits, gicr, pta, true)),
+        }
+    };
+    if let Some(cmd) = cmd {
+        send_host_cmds(&[cmd])?;
+    }
+
+    let mut vms = VM_VPES.lock();
+    let Some(vm) = vms.get_mut(&vm_id) else {
+        return ax_err!(NotFound, "The VM has no vPEs");
+    };
+    let vprop = vm.vprop.as_usize() | 0x780 | (vm.id_bits as usize - 1);
+    let vpe = &mut vm.vpes[vcpu_id];
+    vpe.mapped_on = Some(gicr);
+
+    perform_mmio_write(gicr.base + GICR_VPROPBASER, AccessWidth::Qword, vprop)?;
+    perform_mmio_write(
+        gicr.base + GICR_VPENDBASER,
+        AccessWidth::Qword,
+        vpe.vpt.as_usize() | 0x780 | GICR_VPENDBASER_VALID,
+    )?;
+    vpe.resident = true;
+    Ok(())
+}
+
+/// Makes the vPE of a vCPU non-resident, to be called when the vCPU is put.
+pub fn vpe_put(vm_id: VMId, vcpu_id: VCpuId) -> AxResult {
+    let mut vms = VM_VPES.lock();
+    let Some(vpe) = vms.get_mut(&vm_id).and_then(|vm| vm.vpes.get_mut(vcpu_id)) else {
+        return ax_err!(NotFound, "No vPE for the vCPU");
+    };
+    let Some(gicr) = vpe.mapped_on.filter(|_| vpe.resident) else {
+        return Ok(());
+    };
+
+    let vpendbaser = gicr.base + GICR_VPENDBASER;
+    perform_mmio_write(vpendbaser, AccessWidth::Qword, vpe.vpt.as_usize() | 0x780)?;
+    // The redistributor has written back the pending state once Dirty clears.
+    while perform_mmio_read(vpendbaser, AccessWidth::Qword)? & GICR_VPENDBASER_DIRTY != 0 {
+        core::hint::spin_loop();
+    }
+    vpe.resident = false;
+    Ok(())
+}
+
+/// Sets the property byte of a virtual LPI in the VM's virtual LPI configuration table.
+pub fn set_vlpi_prop(vm_id: VMId, intid: u32, prop: u8) -> AxResult {
+    let vms = VM_VPES.lock();
+    let Some(vm) = vms.get(&vm_id) else {
+        return ax_err!(NotFound, "The VM has no vPEs");
+    };
+    if intid < LPI_BASE || (intid as usize) >> vm.id_bits != 0 {
+        return ax_err!(InvalidInput, "INTID is out of the virtual LPI range");
+    }
+
+    let addr = phys_to_virt(vm.vprop + (intid - LPI_BASE) as usize);
+    unsafe {
+        ptr::write_volatile(addr.as_mut_ptr(), prop);
+    }
+    Ok(())
+}
+
+/// Rewrites a guest ITS command for a VM with vPEs.
+///
+/// Collections are tracked here instead of being mapped on the host: MAPC records the vCPU of a
+/// collection, MAPTI and MAPI become VMAPTI to the vPE of that vCPU, MOVI becomes VMOVI and SYNC
+/// becomes VSYNC. Returns `None` for commands that must not reach the host ITS.
+pub fn translate_cmd(vm_id: VMId, cmd: [u64; 4]) -> Option<[u64; 4]> {
+    let mut vms = VM_VPES.lock();
+    let vm = vms.get_mut(&vm_id)?;
+
+    let code = cmd[0] & 0xff;
+    let device_id = cmd[0] >> 32;
+    let event_id = cmd[1] & 0xffff_ffff;
+    let icid = (cmd[2] & 0xffff) as u16;
+    let vpe_of = |vm: &VmVpes, icid: u16| {
+        let vpe_id = vm.vpe_of_collection(icid);
+        if vpe_id.is_none() {
+            warn!("VM {vm_id} uses unmapped collection {icid:#x}, dropping ITS command {code:#x}");
+        }
+        vpe_id.map(u64::from)
+    };
+
+    match code {
+        ITS_CMD_MAPC => {
+            // RDbase has been translated to the vCPU ID, see `Cmdq::translate_rd_base`.
+            let vcpu_id = ((cmd[2] >> 16) & 0xffff) as VCpuId;
+            if cmd[2] & (1 << 63) != 0 {
+                vm.collections.insert(icid, vcpu_id);
+            } else {
+                vm.collections.remove(&icid);
+            }
+            None
+        }
+        ITS_CMD_MAPTI | ITS_CMD_MAPI => {
+            let vintid = if code == ITS_CMD_MAPTI {
+                cmd[1] >> 32
+            } else {
+                event_id
+            };
+            let vpe_id = vpe_of(vm, icid)?;
+            Some([
+                ITS_CMD_VMAPTI | device_id << 32,
+                event_id | vpe_id << 32,
+                vintid | NO_DOORBELL << 32,
+                0,
+            ])
+        }
+        ITS_CMD_MOVI => {
+            let vpe_id = vpe_of(vm, icid)?;
+            Some([
+                ITS_CMD_VMOVI | device_id << 32,
+                event_id | vpe_id << 32,
+                NO_DOORBELL << 32,
+                0,
+            ])
+        }
+        ITS_CMD_SYNC => {
+            // As for MAPC.
+            let vcpu_id = ((cmd[2] >> 16) & 0xffff) as VCpuId;
+            let vpe_id = vm.vpes.get(vcpu_id)?.id as u64;
+            Some([ITS_CMD_VSYNC, vpe_id << 32, 0, 0])
+        }
+        // The virtual LPI configuration table is written by the hypervisor, and collections only
+        // exist here.
+        ITS_CMD_INVALL | ITS_CMD_MOVALL => None,
+        _ => Some(cmd),
+    }
+}
+
+fn alloc_zeroed(pages: usize, align_pow2: usize) -> AxResult<PhysAddr> {
+    let Some(frame) = axvisor_api::memory::alloc_contiguous_frames(pages, align_pow2) else {
+        return ax_err!(NoMemory, "Failed to allocate GICv4 tables");
+    };
+    unsafe {
+        ptr::write_bytes(phys_to_virt(frame).as_mut_ptr(), 0, pages * PAGE_SIZE_4K);
+    }
+    Ok(frame)
+}
+
+fn alloc_vpe_id() -> Option<u16> {
+    let mut ids = VPE_IDS.lock();
+    let id = (0..=u16::MAX).find(|id| !ids.contains(id))?;
+    ids.insert(id);
+    Some(id)
+}
+
+/// Reads GITS_TYPER.PTA of the host ITS.
+fn host_pta() -> AxResult<bool> {
+    Ok(read_host_gits(GITS_TYPER)? & GITS_TYPER_PTA != 0)
+}
+
+fn vmapp_cmd(vpe: &VPe, id_bits: u32, gicr: &HostGicr, pta: bool, valid: bool) -> [u64; 4] {
+    [
+        ITS_CMD_VMAPP,
+        (vpe.id as u64) << 32,
+        (valid as u64) << 63 | gicr.rd_target(pta),
+        vpe.vpt.as_usize() as u64 | (id_bits as u64 - 1),
+    ]
+}
+
+fn vmovp_cmd(vpe: &VPe, gicr: &HostGicr, pta: bool) -> AxResult<[u64; 4]> {
+    // Without GITS_TYPER.VMOVP the command carries the list of ITSs, just the host one here.
+    let its_list = if read_host_gits(GITS_TYPER)? & GITS_TYPER_VMOVP == 0 {
+        1 << ((read_host_gits(GITS_CTRL)? >> GITS_CTLR_ITS_NUMBER_SHIFT) & 0xf)
+    } else {
+        0
+    };
+    Ok([
+        ITS_CMD_VMOVP,
+        its_list as u64 | (vpe.id as u64) << 32,
+        gicr.rd_target(pta),
+        0,
+    ])
+}
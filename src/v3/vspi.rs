@@ -0,0 +1,222 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate alloc;
+
+use alloc::{vec, vec::Vec};
+
+use axvisor_api::vmm::{VCpuId, VMId};
+use bitmaps::Bitmap;
+
+use super::{
+    host_gicr::{affinity_to_vcpu, mpidr_to_affinity},
+    registers::*,
+};
+
+/// First SPI INTID.
+pub const SPI_BASE: u32 = 32;
+
+/// GICD_IROUTER.Interrupt_Routing_Mode, set if the SPI may be delivered to any PE.
+pub const GICD_IROUTER_IRM: u64 = 1 << 31;
+
+/// A bitmap indexed by INTID, covering INTID 0-1023 and the Extended SPI range.
+#[derive(Clone, Copy, Default)]
+pub struct IrqBitmap {
+    irqs: Bitmap<{ MAX_IRQ_V3 }>,
+    espis: Bitmap<{ ESPI_NUM }>,
+}
+
+impl IrqBitmap {
+    /// Creates an empty bitmap.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Gets the bit of `intid`. INTIDs outside both ranges read as `false`.
+    pub fn get(&self, intid: usize) -> bool {
+        match intid {
+            intid if intid < MAX_IRQ_V3 => self.irqs.get(intid),
+            intid if is_espi(intid) => self.espis.get(intid - ESPI_BASE),
+            _ => false,
+        }
+    }
+
+    /// Sets the bit of `intid`. INTIDs outside both ranges are ignored.
+    pub fn set(&mut self, intid: usize, value: bool) {
+        match intid {
+            intid if intid < MAX_IRQ_V3 => {
+                self.irqs.set(intid, value);
+            }
+            intid if is_espi(intid) => {
+                self.espis.set(intid - ESPI_BASE, value);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Checks if an INTID is in the Extended SPI range.
+pub fn is_espi(intid: usize) -> bool {
+    (ESPI_BASE..ESPI_BASE + ESPI_NUM).contains(&intid)
+}
+
+/// State of a virtual (emulated, not passed through) SPI.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct VirtualSpi {
+    /// GICD_ISENABLER bit.
+    pub enabled: bool,
+    /// GICD_ISPENDR bit.
+    ///
+    /// Cleared once the SPI is handed to a list register, the pending and active states are
+    /// tracked by the list register from then on.
+    pub pending: bool,
+    /// GICD_ISACTIVER bit.
+    pub active: bool,
+    /// Whether the SPI is edge-triggered (GICD_ICFGR).
+    pub edge: bool,
+    /// GICD_IPRIORITYR byte.
+    pub priority: u8,
+    /// GICD_IROUTER value.
+    pub route: u64,
+}
+
+/// Bit-field GICD registers emulated for virtual SPIs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VirtualSpiReg {
+    /// GICD_ISENABLER.
+    SetEnable,
+    /// GICD_ICENABLER.
+    ClearEnable,
+    /// GICD_ISPENDR.
+    SetPending,
+    /// GICD_ICPENDR.
+    ClearPending,
+    /// GICD_ISACTIVER.
+    SetActive,
+    /// GICD_ICACTIVER.
+    ClearActive,
+    /// GICD_ICFGR.
+    Config,
+    /// GICD_IPRIORITYR.
+    Priority,
+}
+
+impl VirtualSpiReg {
+    /// Decodes the register at `reg` in the GICD frame.
+    ///
+    /// Both the classic and the extended SPI registers are decoded.
+    pub fn from_reg(reg: usize) -> Option<Self> {
+        let in_range = |range: core::ops::Range<usize>, ext_range: core::ops::Range<usize>| {
+            range.contains(&reg) || ext_range.contains(&reg)
+        };
+
+        match reg {
+            _ if in_range(GICD_ISENABLER_RANGE, GICD_ISENABLERNE_RANGE) => Some(Self::SetEnable),
+            _ if in_range(GICD_ICENABLER_RANGE, GICD_ICENABLERNE_RANGE) => Some(Self::ClearEnable),
+            _ if in_range(GICD_ISPENDR_RANGE, GICD_ISPENDRNE_RANGE) => Some(Self::SetPending),
+            _ if in_range(GICD_ICPENDR_RANGE, GICD_ICPENDRNE_RANGE) => Some(Self::ClearPending),
+            _ if in_range(GICD_ISACTIVER_RANGE, GICD_ISACTIVERNE_RANGE) => Some(Self::SetActive),
+            _ if in_range(GICD_ICACTIVER_RANGE, GICD_ICACTIVERNE_RANGE) => Some(Self::ClearActive),
+            _ if in_range(GICD_ICFGR_RANGE, GICD_ICFGRNE_RANGE) => Some(Self::Config),
+            _ if in_range(GICD_IPRIORITYR_RANGE, GICD_IPRIORITYRNE_RANGE) => Some(Self::Priority),
+            _ => None,
+        }
+    }
+}
+
+impl VirtualSpi {
+    /// Returns the bits of this SPI in `reg`, right-aligned.
+    pub fn read(&self, reg: VirtualSpiReg) -> usize {
+        match reg {
+            VirtualSpiReg::SetEnable | VirtualSpiReg::ClearEnable => self.enabled as usize,
+            VirtualSpiReg::SetPending | VirtualSpiReg::ClearPending => self.pending as usize,
+            VirtualSpiReg::SetActive | VirtualSpiReg::ClearActive => self.active as usize,
+            VirtualSpiReg::Config => (self.edge as usize) << 1,
+            VirtualSpiReg::Priority => self.priority as usize,
+        }
+    }
+
+    /// Writes the bits of this SPI in `reg`, right-aligned.
+    pub fn write(&mut self, reg: VirtualSpiReg, bits: usize) {
+        let set = bits & 1 != 0;
+        match reg {
+            VirtualSpiReg::SetEnable => self.enabled |= set,
+            VirtualSpiReg::ClearEnable => self.enabled &= !set,
+            VirtualSpiReg::SetPending => self.pending |= set,
+            VirtualSpiReg::ClearPending => self.pending &= !set,
+            VirtualSpiReg::SetActive => self.active |= set,
+            VirtualSpiReg::ClearActive => self.active &= !set,
+            VirtualSpiReg::Config => self.edge = bits & 0b10 != 0,
+            VirtualSpiReg::Priority => self.priority = bits as u8,
+        }
+    }
+
+    /// Whether the SPI should be handed to a list register now.
+    pub fn should_inject(&self) -> bool {
+        self.enabled && self.pending && !self.active
+    }
+
+    /// The vCPU of VM `vm_id` this SPI is routed to, looked up by the virtual MPIDR_EL1 of its
+    /// vCPUs. SPIs in 1-of-N mode go to vCPU 0.
+    ///
+    /// Returns `None` if the route names no vCPU of the VM.
+    pub fn target_vcpu(&self, vm_id: VMId) -> Option<VCpuId> {
+        if self.route & GICD_IROUTER_IRM != 0 {
+            Some(0)
+        } else {
+            // GICD_IROUTER keeps the affinity fields where MPIDR_EL1 does.
+            affinity_to_vcpu(vm_id, mpidr_to_affinity(self.route))
+        }
+    }
+}
+
+/// Virtual state of all SPIs and Extended SPIs of a VM, indexed by INTID.
+pub struct VirtualSpis {
+    spis: Vec<VirtualSpi>,
+}
+
+impl VirtualSpis {
+    /// Creates the virtual state for SPIs `SPI_BASE..MAX_IRQ_V3` and all Extended SPIs.
+    pub fn new() -> Self {
+        Self {
+            spis: vec![VirtualSpi::default(); MAX_IRQ_V3 - SPI_BASE as usize + ESPI_NUM],
+        }
+    }
+
+    /// Maps an INTID to its index in `spis`.
+    fn index(irq: u32) -> Option<usize> {
+        let irq = irq as usize;
+        match irq {
+            irq if (SPI_BASE as usize..MAX_IRQ_V3).contains(&irq) => Some(irq - SPI_BASE as usize),
+            irq if is_espi(irq) => Some(MAX_IRQ_V3 - SPI_BASE as usize + irq - ESPI_BASE),
+            _ => None,
+        }
+    }
+
+    /// Gets the state of an SPI.
+    pub fn get(&self, irq: u32) -> Option<&VirtualSpi> {
+        self.spis.get(Self::index(irq)?)
+    }
+
+    /// Gets the mutable state of an SPI.
+    pub fn get_mut(&mut self, irq: u32) -> Option<&mut VirtualSpi> {
+        self.spis.get_mut(Self::index(irq)?)
+    }
+}
+
+impl Default for VirtualSpis {
+    fn default() -> Self {
+        Self::new()
+    }
+}